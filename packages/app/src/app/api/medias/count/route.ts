@@ -1,6 +1,6 @@
 import { ListObjectsV2Command } from "@aws-sdk/client-s3";
 import { NextResponse } from "next/server";
-import { B2_S3_BUCKET, s3 } from "@/lib/s3";
+import { B2_S3_BUCKET, isMediaKey, s3 } from "@/lib/s3";
 
 export async function GET() {
   try {
@@ -16,7 +16,9 @@ export async function GET() {
 
       const response = await s3.send(cmd);
 
-      const count = (response.Contents || []).length;
+      const count = (response.Contents || []).filter((item) =>
+        isMediaKey(item.Key),
+      ).length;
 
       totalCount += count;
       continuationToken = response.NextContinuationToken;
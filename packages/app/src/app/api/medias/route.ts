@@ -4,7 +4,7 @@ import {
   ListObjectsV2Command,
 } from "@aws-sdk/client-s3";
 import { type NextRequest, NextResponse } from "next/server";
-import { B2_S3_BUCKET, s3 } from "@/lib/s3";
+import { B2_S3_BUCKET, isMediaKey, s3 } from "@/lib/s3";
 
 async function getAllMediasSorted(): Promise<_Object[]> {
   // 全てのオブジェクトを取得
@@ -20,7 +20,9 @@ async function getAllMediasSorted(): Promise<_Object[]> {
 
     const response = await s3.send(command);
     if (response.Contents) {
-      allItems = allItems.concat(response.Contents);
+      allItems = allItems.concat(
+        response.Contents.filter((item) => isMediaKey(item.Key)),
+      );
     }
     continuationToken = response.NextContinuationToken;
   } while (continuationToken);
@@ -2,6 +2,12 @@ import { S3Client } from "@aws-sdk/client-s3";
 
 export const B2_S3_REGION = "us-west-004";
 export const B2_S3_BUCKET = "family-photo";
+// media-processor が変換結果を保存するプレフィックス（メディア一覧には含めない）
+export const VARIANTS_PREFIX = "variants/";
+
+export function isMediaKey(key: string | undefined): boolean {
+  return key !== undefined && !key.startsWith(VARIANTS_PREFIX);
+}
 
 export const s3 = new S3Client({
   credentials: {
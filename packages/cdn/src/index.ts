@@ -205,6 +205,43 @@ app.use("*", async (c, next) => {
 
 const MEDIA_PROCESSOR_TIMEOUT_MS = 30_000;
 const ALLOWED_FORMATS = new Set(["jpg", "jpeg", "png", "webp", "avif"]);
+const BOOLEAN_VALUES = new Set(["true", "false"]);
+const ALLOWED_FITS = new Set([
+  "contain",
+  "inside",
+  "cover",
+  "fill",
+  "outside",
+  "pad",
+]);
+const ALLOWED_GRAVITIES = new Set([
+  "center",
+  "centre",
+  "north",
+  "south",
+  "east",
+  "west",
+  "northeast",
+  "northwest",
+  "southeast",
+  "southwest",
+  "smart",
+]);
+const ASPECT_RATIO_PATTERN = /^\d+(\.\d+)?:\d+(\.\d+)?$/;
+const ALLOWED_COLOR_SPACES = new Set(["srgb", "p3", "display-p3", "keep"]);
+const ALLOWED_FLIPS = new Set(["h", "v", "hv", "vh"]);
+const ALLOWED_FILTERS = new Set([
+  "nearest",
+  "bilinear",
+  "catmull-rom",
+  "mitchell",
+  "lanczos3",
+  "box",
+]);
+const MAX_SHARPEN = 10;
+const HEX_COLOR_PATTERN = /^#?([0-9a-f]{6}|[0-9a-f]{8})$/i;
+// x,y,w,h（各成分はピクセル値の整数または百分率）
+const CROP_PATTERN = /^(\d+|\d+(\.\d+)?%)(,(\d+|\d+(\.\d+)?%)){3}$/;
 
 const IMAGE_EXTENSIONS = new Set([
   ".jpg",
@@ -216,6 +253,8 @@ const IMAGE_EXTENSIONS = new Set([
   ".bmp",
   ".tiff",
   ".tif",
+  ".heic",
+  ".heif",
 ]);
 const VIDEO_EXTENSIONS = new Set([".mp4", ".mov", ".avi", ".webm", ".mkv"]);
 
@@ -293,11 +332,155 @@ function validateQuery(query: Record<string, string>): string | null {
     }
   }
 
+  if (query.page !== undefined) {
+    const page = Number(query.page);
+    if (!Number.isInteger(page) || page < 0) {
+      return "page は 0 以上の整数で指定してください";
+    }
+  }
+
+  if (query.lossless !== undefined) {
+    if (!BOOLEAN_VALUES.has(query.lossless.toLowerCase())) {
+      return "lossless は true または false で指定してください";
+    }
+  }
+
+  if (query.fit !== undefined) {
+    if (!ALLOWED_FITS.has(query.fit.toLowerCase())) {
+      return "サポートされていない fit です（対応: contain, inside, cover, fill, outside, pad）";
+    }
+  }
+
+  if (query.gravity !== undefined) {
+    if (!ALLOWED_GRAVITIES.has(query.gravity.toLowerCase())) {
+      return "サポートされていない gravity です";
+    }
+  }
+
+  for (const param of ["fx", "fy"] as const) {
+    if (query[param] !== undefined) {
+      const value = Number(query[param]);
+      if (!Number.isFinite(value) || value < 0 || value > 1) {
+        return `${param} は 0〜1 の数値で指定してください`;
+      }
+    }
+  }
+
+  if (query.ar !== undefined) {
+    if (!ASPECT_RATIO_PATTERN.test(query.ar)) {
+      return "ar は 幅:高さ の形式で指定してください（例: 16:9）";
+    }
+  }
+
+  if (query.crop !== undefined) {
+    if (!CROP_PATTERN.test(query.crop)) {
+      return "crop は x,y,w,h の形式で指定してください（例: 100,50,800,600 / 10%,10%,50%,50%）";
+    }
+  }
+
+  if (query.rot !== undefined) {
+    if (!Number.isFinite(Number(query.rot)) || query.rot.trim() === "") {
+      return "rot は角度（度）を数値で指定してください";
+    }
+  }
+
+  if (query.flip !== undefined) {
+    if (!ALLOWED_FLIPS.has(query.flip.toLowerCase())) {
+      return "flip は h, v, hv のいずれかで指定してください";
+    }
+  }
+
+  if (query.bg !== undefined) {
+    if (!HEX_COLOR_PATTERN.test(query.bg)) {
+      return "bg は 16 進カラー（RRGGBB または RRGGBBAA）で指定してください";
+    }
+  }
+
+  if (query.cs !== undefined) {
+    if (!ALLOWED_COLOR_SPACES.has(query.cs.toLowerCase())) {
+      return "サポートされていない cs です（対応: srgb, p3, keep）";
+    }
+  }
+
+  if (query.linear !== undefined) {
+    if (!BOOLEAN_VALUES.has(query.linear.toLowerCase())) {
+      return "linear は true または false で指定してください";
+    }
+  }
+
+  if (query.filter !== undefined) {
+    if (!ALLOWED_FILTERS.has(query.filter.toLowerCase())) {
+      return "サポートされていない filter です（対応: nearest, bilinear, catmull-rom, mitchell, lanczos3, box）";
+    }
+  }
+
+  if (query.sharpen !== undefined) {
+    const sharpen = Number(query.sharpen);
+    if (
+      !Number.isFinite(sharpen) ||
+      query.sharpen.trim() === "" ||
+      sharpen < 0 ||
+      sharpen > MAX_SHARPEN
+    ) {
+      return `sharpen は 0〜${MAX_SHARPEN} の数値で指定してください`;
+    }
+  }
+
+  if (query.speed !== undefined) {
+    const speed = Number(query.speed);
+    if (!Number.isInteger(speed) || speed < 1 || speed > 10) {
+      return "speed は 1〜10 の整数で指定してください";
+    }
+  }
+
+  if (query.enlarge !== undefined) {
+    if (!BOOLEAN_VALUES.has(query.enlarge.toLowerCase())) {
+      return "enlarge は true または false で指定してください";
+    }
+  }
+
   return null;
 }
 
 // 許可されたクエリパラメータのみでキャッシュキーを構築（キャッシュポイズニング防止）
-const TRANSFORM_PARAMS = ["w", "h", "f", "q"] as const;
+const TRANSFORM_PARAMS = [
+  "w",
+  "h",
+  "f",
+  "q",
+  "page",
+  "lossless",
+  "fit",
+  "gravity",
+  "fx",
+  "fy",
+  "ar",
+  "crop",
+  "rot",
+  "flip",
+  "bg",
+  "cs",
+  "linear",
+  "filter",
+  "sharpen",
+  "enlarge",
+  "speed",
+] as const;
+// 数値ではなく文字列として正規化（小文字化）するパラメータ
+const STRING_PARAMS = new Set<string>([
+  "f",
+  "lossless",
+  "fit",
+  "gravity",
+  "ar",
+  "crop",
+  "flip",
+  "bg",
+  "cs",
+  "linear",
+  "filter",
+  "enlarge",
+]);
 
 function buildCacheKey(url: string, download: boolean): Request {
   const src = new URL(url);
@@ -308,9 +491,10 @@ function buildCacheKey(url: string, download: boolean): Request {
     for (const param of TRANSFORM_PARAMS) {
       const value = src.searchParams.get(param);
       if (value === null) continue;
-      // 正規化: 数値パラメータは Number() で、f は小文字化
-      const normalized =
-        param === "f" ? value.toLowerCase() : String(Number(value));
+      // 正規化: 数値パラメータは Number() で、文字列パラメータは小文字化
+      const normalized = STRING_PARAMS.has(param)
+        ? value.toLowerCase()
+        : String(Number(value));
       cacheUrl.searchParams.set(param, normalized);
     }
   }
@@ -345,10 +529,19 @@ function fetchFromStorageProxy(
 // オリジンエラーレスポンスを適切な CDN エラーにマッピング
 function mapOriginError(status: number): { message: string; status: number } {
   switch (status) {
+    case 400:
+      return { message: "変換パラメータが不正です", status: 400 };
     case 404:
       return { message: "指定されたメディアが見つかりません", status: 404 };
+    case 413:
+      return { message: "原本の解像度が大きすぎます", status: 413 };
     case 422:
       return { message: "メディアの変換に失敗しました", status: 422 };
+    case 503:
+      return {
+        message: "混雑しています。しばらくしてから再度お試しください",
+        status: 503,
+      };
     case 500:
       return {
         message: "メディアの取得に失敗しました",
@@ -489,6 +682,11 @@ async function handleMedia(c: Context<HonoEnv>): Promise<Response> {
       `オリジンエラー: status=${originResponse.status} key=${key} body=${originBody}`,
     );
     const { message, status } = mapOriginError(originResponse.status);
+    // 過負荷時はオリジンの Retry-After をそのままクライアントに伝える
+    const retryAfter = originResponse.headers.get("Retry-After");
+    if (status === 503 && retryAfter) {
+      c.header("Retry-After", retryAfter);
+    }
     return c.json({ error: message }, status as ContentfulStatusCode);
   }
 
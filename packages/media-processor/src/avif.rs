@@ -0,0 +1,344 @@
+//! AVIF コンテナ（ISOBMFF）の後処理。
+//!
+//! ravif は色域を BT.709 に固定し、既定値と同じ場合は colr ボックスを省略するため、
+//! Display P3 で出力するときは colr (nclx) ボックスを追加して色域を書き換える。
+
+use std::ops::Range;
+
+/// CICP color_primaries の Display P3 (SMPTE EG 432-1)。
+pub const DISPLAY_P3_PRIMARIES: u16 = 12;
+
+/// ravif（image クレートの AvifEncoder の既定設定）が AV1 ペイロードに書く値。
+/// colr ボックスはペイロードより優先されるため、色域以外はこれに合わせる。
+const TRANSFER_SRGB: u16 = 13;
+const MATRIX_BT601: u16 = 6;
+const COLR_BOX_LEN: usize = 8 + 4 + 2 + 2 + 2 + 1;
+
+struct BoxHeader {
+    start: usize,
+    size: usize,
+    kind: [u8; 4],
+}
+
+impl BoxHeader {
+    fn end(&self) -> usize {
+        self.start + self.size
+    }
+
+    /// ヘッダ（FullBox の場合は version / flags を含む）を除いた中身の範囲。
+    fn body(&self, full_box: bool) -> Range<usize> {
+        self.start + if full_box { 12 } else { 8 }..self.end()
+    }
+}
+
+/// AVIF のプライマリ画像の色域（CICP color_primaries）を書き換える。
+///
+/// 想定外の構造（64bit サイズのボックス等）の場合は None を返す。
+pub fn set_color_primaries(mut avif: Vec<u8>, color_primaries: u16) -> Option<Vec<u8>> {
+    let top = children(&avif, 0..avif.len())?;
+    let meta = find(&top, b"meta")?;
+    let meta_children = children(&avif, meta.body(true))?;
+    let iprp = find(&meta_children, b"iprp")?;
+    let iprp_children = children(&avif, iprp.body(false))?;
+    let ipco = find(&iprp_children, b"ipco")?;
+    let ipma = find(&iprp_children, b"ipma")?;
+    let properties = children(&avif, ipco.body(false))?;
+
+    // 既に colr (nclx) がある場合は固定長のフィールドをその場で書き換える
+    if let Some(colr) = find(&properties, b"colr")
+        && avif.get(colr.start + 8..colr.start + 12)? == b"nclx"
+    {
+        write_uint(&mut avif, colr.start + 12, 2, color_primaries as u64);
+        return Some(avif);
+    }
+
+    let pitm = find(&meta_children, b"pitm")?;
+    let pitm_id_len = if *avif.get(pitm.start + 8)? == 0 {
+        2
+    } else {
+        4
+    };
+    let primary_item = read_uint(&avif, pitm.body(true).start, pitm_id_len)?;
+
+    // ipma でプライマリ画像の関連付けを探す
+    let ipma_version = *avif.get(ipma.start + 8)?;
+    let ipma_flags = read_uint(&avif, ipma.start + 9, 3)?;
+    let item_id_len = if ipma_version < 1 { 2 } else { 4 };
+    let assoc_len = if ipma_flags & 1 == 1 { 2 } else { 1 };
+
+    let mut pos = ipma.body(true).start;
+    let entry_count = read_uint(&avif, pos, 4)?;
+    pos += 4;
+    let mut insert_assoc_at = None;
+    for _ in 0..entry_count {
+        let item_id = read_uint(&avif, pos, item_id_len)?;
+        let count_pos = pos + item_id_len;
+        let count = *avif.get(count_pos)? as usize;
+        pos = count_pos + 1 + count * assoc_len;
+        if item_id == primary_item {
+            insert_assoc_at = Some((count_pos, pos));
+        }
+    }
+    let (count_pos, assoc_pos) = insert_assoc_at?;
+
+    let delta = COLR_BOX_LEN + assoc_len;
+    let insert_point = ipco.end();
+
+    // サイズ・オフセットの書き換えは挿入前の位置で行う（固定長フィールドのみ）
+    shift_iloc_offsets(&mut avif, &meta_children, insert_point, delta)?;
+    add_to_box_size(&mut avif, meta, delta);
+    add_to_box_size(&mut avif, iprp, delta);
+    add_to_box_size(&mut avif, ipco, COLR_BOX_LEN);
+    add_to_box_size(&mut avif, ipma, assoc_len);
+    avif[count_pos] = avif[count_pos].checked_add(1)?;
+
+    // 新しいプロパティは ipco の末尾に追加する（インデックスは 1 始まり、essential ではない）
+    let property_index = properties.len() as u64 + 1;
+    let assoc = match assoc_len {
+        1 if property_index < 0x80 => vec![property_index as u8],
+        2 if property_index < 0x8000 => (property_index as u16).to_be_bytes().to_vec(),
+        _ => return None,
+    };
+
+    let mut colr = Vec::with_capacity(COLR_BOX_LEN);
+    colr.extend_from_slice(&(COLR_BOX_LEN as u32).to_be_bytes());
+    colr.extend_from_slice(b"colrnclx");
+    colr.extend_from_slice(&color_primaries.to_be_bytes());
+    colr.extend_from_slice(&TRANSFER_SRGB.to_be_bytes());
+    colr.extend_from_slice(&MATRIX_BT601.to_be_bytes());
+    colr.push(0x80); // full_range_flag
+
+    // 後ろの位置から挿入して前の位置をずらさない
+    avif.splice(assoc_pos..assoc_pos, assoc);
+    avif.splice(insert_point..insert_point, colr);
+    Some(avif)
+}
+
+/// `insert_point` 以降を指す iloc のファイルオフセットを `delta` だけずらす。
+fn shift_iloc_offsets(
+    data: &mut [u8],
+    meta_children: &[BoxHeader],
+    insert_point: usize,
+    delta: usize,
+) -> Option<()> {
+    let iloc = find(meta_children, b"iloc")?;
+    let version = *data.get(iloc.start + 8)?;
+    let mut pos = iloc.body(true).start;
+
+    let sizes = *data.get(pos)?;
+    let (offset_size, length_size) = ((sizes >> 4) as usize, (sizes & 0x0f) as usize);
+    let sizes = *data.get(pos + 1)?;
+    let base_offset_size = (sizes >> 4) as usize;
+    let index_size = if version >= 1 {
+        (sizes & 0x0f) as usize
+    } else {
+        0
+    };
+    pos += 2;
+
+    let id_len = if version < 2 { 2 } else { 4 };
+    let item_count = read_uint(data, pos, id_len)?;
+    pos += id_len;
+
+    let shift = |data: &mut [u8], at: usize, len: usize| -> Option<()> {
+        let value = read_uint(data, at, len)?;
+        if len > 0 && value as usize >= insert_point {
+            write_uint(data, at, len, value + delta as u64);
+        }
+        Some(())
+    };
+
+    for _ in 0..item_count {
+        pos += id_len;
+        let construction_method = if version >= 1 {
+            let method = read_uint(data, pos, 2)? & 0x0f;
+            pos += 2;
+            method
+        } else {
+            0
+        };
+        pos += 2; // data_reference_index
+
+        let base_offset = read_uint(data, pos, base_offset_size)?;
+        // ファイルオフセット（construction_method 0）のみ対象。idat 内のオフセットは変わらない
+        let file_offset = construction_method == 0;
+        if file_offset && base_offset > 0 {
+            shift(data, pos, base_offset_size)?;
+        }
+        pos += base_offset_size;
+
+        let extent_count = read_uint(data, pos, 2)?;
+        pos += 2;
+        for _ in 0..extent_count {
+            pos += index_size;
+            if file_offset && base_offset == 0 {
+                shift(data, pos, offset_size)?;
+            }
+            pos += offset_size + length_size;
+        }
+    }
+    Some(())
+}
+
+/// `range` 内の子ボックスを列挙する。64bit サイズ・サイズ 0（末尾まで）のボックスは扱わない。
+fn children(data: &[u8], range: Range<usize>) -> Option<Vec<BoxHeader>> {
+    let mut boxes = Vec::new();
+    let mut pos = range.start;
+    while pos < range.end {
+        let size = read_uint(data, pos, 4)? as usize;
+        if size < 8 || pos + size > range.end {
+            return None;
+        }
+        let kind = data.get(pos + 4..pos + 8)?.try_into().ok()?;
+        boxes.push(BoxHeader {
+            start: pos,
+            size,
+            kind,
+        });
+        pos += size;
+    }
+    Some(boxes)
+}
+
+fn find<'a>(boxes: &'a [BoxHeader], kind: &[u8; 4]) -> Option<&'a BoxHeader> {
+    boxes.iter().find(|b| &b.kind == kind)
+}
+
+fn add_to_box_size(data: &mut [u8], header: &BoxHeader, delta: usize) {
+    write_uint(data, header.start, 4, (header.size + delta) as u64);
+}
+
+fn read_uint(data: &[u8], pos: usize, len: usize) -> Option<u64> {
+    let bytes = data.get(pos..pos + len)?;
+    Some(bytes.iter().fold(0u64, |acc, &b| (acc << 8) | b as u64))
+}
+
+fn write_uint(data: &mut [u8], pos: usize, len: usize, value: u64) {
+    for i in 0..len {
+        data[pos + i] = (value >> (8 * (len - 1 - i))) as u8;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use image::codecs::avif::AvifEncoder;
+    use image::{DynamicImage, Rgba, RgbaImage};
+
+    use super::*;
+
+    fn encode(alpha: bool) -> Vec<u8> {
+        let img = RgbaImage::from_fn(16, 8, |x, y| {
+            let a = if alpha { (x * 16) as u8 } else { 255 };
+            Rgba([(x * 16) as u8, (y * 32) as u8, 128, a])
+        });
+        let img = if alpha {
+            DynamicImage::ImageRgba8(img)
+        } else {
+            DynamicImage::ImageRgb8(DynamicImage::ImageRgba8(img).to_rgb8())
+        };
+        let mut buf = Cursor::new(Vec::new());
+        img.write_with_encoder(AvifEncoder::new_with_speed_quality(&mut buf, 10, 80))
+            .unwrap();
+        buf.into_inner()
+    }
+
+    fn decode(avif: &[u8]) -> (heic::ImageInfo, Vec<u8>) {
+        let info = heic::ImageInfo::from_bytes(avif).unwrap();
+        let output = heic::DecoderConfig::new()
+            .decode(avif, heic::PixelLayout::Rgba8)
+            .unwrap();
+        (info, output.data)
+    }
+
+    /// iloc のオフセットを 8 バイトに広げる（ravif は常に 4 バイトで書く）。
+    fn widen_iloc_offsets(avif: &[u8]) -> Vec<u8> {
+        let top = children(avif, 0..avif.len()).unwrap();
+        let meta = find(&top, b"meta").unwrap();
+        let meta_children = children(avif, meta.body(true)).unwrap();
+        let iloc = find(&meta_children, b"iloc").unwrap();
+        let body = iloc.body(true);
+        assert_eq!(avif[iloc.start + 8], 0, "iloc version");
+        assert_eq!(avif[body.start], 0x44, "offset_size / length_size");
+        assert_eq!(avif[body.start + 1], 0, "base_offset_size");
+
+        let item_count = read_uint(avif, body.start + 2, 2).unwrap();
+        let mut items = Vec::new();
+        let mut pos = body.start + 4;
+        for _ in 0..item_count {
+            let header = &avif[pos..pos + 4]; // item_ID, data_reference_index
+            let extent_count = read_uint(avif, pos + 4, 2).unwrap() as usize;
+            let extents: Vec<_> = (0..extent_count)
+                .map(|i| {
+                    let at = pos + 6 + i * 8;
+                    (read_uint(avif, at, 4).unwrap(), &avif[at + 4..at + 8])
+                })
+                .collect();
+            items.push((header, extents));
+            pos += 6 + extent_count * 8;
+        }
+        let growth: usize = items.iter().map(|(_, extents)| extents.len() * 4).sum();
+
+        let mut new_iloc = avif[iloc.start..body.start].to_vec();
+        new_iloc.extend_from_slice(&[0x84, 0]);
+        new_iloc.extend_from_slice(&(item_count as u16).to_be_bytes());
+        for (header, extents) in &items {
+            new_iloc.extend_from_slice(header);
+            new_iloc.extend_from_slice(&(extents.len() as u16).to_be_bytes());
+            for (offset, length) in extents {
+                // 画像データは meta より後ろの mdat にあるため、iloc が伸びた分だけずれる
+                new_iloc.extend_from_slice(&(offset + growth as u64).to_be_bytes());
+                new_iloc.extend_from_slice(length);
+            }
+        }
+        let iloc_size = new_iloc.len() as u64;
+        write_uint(&mut new_iloc, 0, 4, iloc_size);
+
+        let mut out = avif[..iloc.start].to_vec();
+        out.extend_from_slice(&new_iloc);
+        out.extend_from_slice(&avif[iloc.end()..]);
+        add_to_box_size(&mut out, meta, growth);
+        out
+    }
+
+    #[test]
+    fn adds_colr_to_primary_item() {
+        for alpha in [false, true] {
+            let avif = encode(alpha);
+            let (info, pixels) = decode(&avif);
+            // ravif は既定の BT.709 では colr を省略するため、コンテナ上は未指定（2）
+            assert_eq!(info.color_primaries, 2);
+
+            let rewritten = set_color_primaries(avif, DISPLAY_P3_PRIMARIES).unwrap();
+            let (info, rewritten_pixels) = decode(&rewritten);
+            assert_eq!(info.color_primaries, DISPLAY_P3_PRIMARIES);
+            assert_eq!(info.transfer_characteristics, TRANSFER_SRGB);
+            assert_eq!(info.has_alpha, alpha);
+            assert_eq!(rewritten_pixels, pixels, "alpha: {alpha}");
+        }
+    }
+
+    #[test]
+    fn rewrites_existing_colr_in_place() {
+        let avif = set_color_primaries(encode(false), DISPLAY_P3_PRIMARIES).unwrap();
+        let len = avif.len();
+
+        let rewritten = set_color_primaries(avif, 1).unwrap();
+        assert_eq!(rewritten.len(), len);
+        assert_eq!(decode(&rewritten).0.color_primaries, 1);
+    }
+
+    #[test]
+    fn shifts_8_byte_iloc_offsets() {
+        for alpha in [false, true] {
+            let avif = widen_iloc_offsets(&encode(alpha));
+            let (_, pixels) = decode(&avif);
+
+            let rewritten = set_color_primaries(avif, DISPLAY_P3_PRIMARIES).unwrap();
+            let (info, rewritten_pixels) = decode(&rewritten);
+            assert_eq!(info.color_primaries, DISPLAY_P3_PRIMARIES);
+            assert_eq!(rewritten_pixels, pixels, "alpha: {alpha}");
+        }
+    }
+}
@@ -0,0 +1,192 @@
+//! バイト数で容量を制限するプロセス内の LRU キャッシュ。
+//!
+//! srcset の 3〜4 サイズを生成するときは同じ原本を続けて使うため、取得した原本と
+//! 小さな変換結果をインスタンスのメモリに保持する。容量を超えたら最も古く使われたものから捨てる。
+
+use std::borrow::Borrow;
+use std::hash::Hash;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use lru::LruCache;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+/// 容量に対してこの割合を超える値は保持しない（1 つの巨大な原本がキャッシュ全体を押し流さないように）。
+const MAX_ENTRY_FRACTION: usize = 8;
+
+pub struct ByteLru<K: Hash + Eq, V> {
+    inner: Arc<Mutex<Inner<K, V>>>,
+    hits: Arc<AtomicU64>,
+    misses: Arc<AtomicU64>,
+    capacity_bytes: usize,
+}
+
+struct Inner<K: Hash + Eq, V> {
+    entries: LruCache<K, (V, usize)>,
+    bytes: usize,
+}
+
+/// キャッシュの利用状況。
+#[derive(Debug, Serialize)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub entries: usize,
+    pub bytes: usize,
+    pub capacity_bytes: usize,
+}
+
+impl<K: Hash + Eq, V> Clone for ByteLru<K, V> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            hits: self.hits.clone(),
+            misses: self.misses.clone(),
+            capacity_bytes: self.capacity_bytes,
+        }
+    }
+}
+
+impl<K: Hash + Eq, V: Clone> ByteLru<K, V> {
+    /// 容量 `capacity_bytes` のキャッシュを作成する。0 ならキャッシュしない。
+    pub fn new(capacity_bytes: usize) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(Inner {
+                entries: LruCache::unbounded(),
+                bytes: 0,
+            })),
+            hits: Arc::new(AtomicU64::new(0)),
+            misses: Arc::new(AtomicU64::new(0)),
+            capacity_bytes,
+        }
+    }
+
+    /// 値を取り出し、最近使ったものとして扱う。ヒット・ミスを数える。
+    pub fn get<Q>(&self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let value = self.lock().entries.get(key).map(|(value, _)| value.clone());
+        let counter = if value.is_some() {
+            &self.hits
+        } else {
+            &self.misses
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        value
+    }
+
+    /// `size` バイトの値を追加する。容量を超えた分は古いものから捨てる。
+    pub fn insert(&self, key: K, value: V, size: usize) {
+        if size == 0 || size > self.capacity_bytes / MAX_ENTRY_FRACTION {
+            return;
+        }
+
+        let mut inner = self.lock();
+        if let Some((_, old_size)) = inner.entries.put(key, (value, size)) {
+            inner.bytes -= old_size;
+        }
+        inner.bytes += size;
+        while inner.bytes > self.capacity_bytes {
+            let Some((_, (_, evicted_size))) = inner.entries.pop_lru() else {
+                break;
+            };
+            inner.bytes -= evicted_size;
+        }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let inner = self.lock();
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            entries: inner.entries.len(),
+            bytes: inner.bytes,
+            capacity_bytes: self.capacity_bytes,
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Inner<K, V>> {
+        crate::sync::lock(&self.inner)
+    }
+}
+
+/// 変換結果のキャッシュキー（オブジェクトキーとパース済みのパラメータ）を、ファイル名や
+/// オブジェクトキーに使える 16 進数の SHA-256 にする。
+pub fn key_digest(cache_key: &str) -> String {
+    format!("{:x}", Sha256::digest(cache_key.as_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tracks_bytes_of_inserted_and_replaced_values() {
+        let cache = ByteLru::new(800);
+        cache.insert("a", 1, 40);
+        cache.insert("b", 2, 60);
+        assert_eq!(cache.stats().bytes, 100);
+
+        // 置き換えた値は古いサイズを差し引く
+        cache.insert("a", 3, 10);
+        let stats = cache.stats();
+        assert_eq!((stats.entries, stats.bytes), (2, 70));
+
+        assert_eq!(cache.get("a"), Some(3));
+        assert_eq!(cache.get("c"), None);
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses), (1, 1));
+    }
+
+    #[test]
+    fn skips_values_larger_than_an_eighth_of_capacity() {
+        let cache = ByteLru::new(800);
+        cache.insert("large", 1, 101);
+        cache.insert("empty", 2, 0);
+        cache.insert("max", 3, 100);
+        assert_eq!(cache.get("large"), None);
+        assert_eq!(cache.get("empty"), None);
+        assert_eq!(cache.get("max"), Some(3));
+        assert_eq!(cache.stats().bytes, 100);
+
+        let disabled = ByteLru::new(0);
+        disabled.insert("a", 1, 1);
+        assert_eq!(disabled.stats().entries, 0);
+    }
+
+    #[test]
+    fn evicts_least_recently_used_until_within_capacity() {
+        let cache = ByteLru::new(800);
+        for key in 0..8 {
+            cache.insert(key, key, 100);
+        }
+        assert_eq!(cache.stats().bytes, 800);
+
+        // 0 を使うと、最も古く使われたのは 1 になる
+        assert_eq!(cache.get(&0), Some(0));
+        cache.insert(8, 8, 100);
+        assert_eq!(cache.get(&1), None);
+        assert_eq!(cache.get(&0), Some(0));
+
+        assert_eq!(cache.stats().bytes, 800);
+    }
+
+    #[test]
+    fn evicts_several_small_values_for_a_large_one() {
+        let cache = ByteLru::new(800);
+        cache.insert(0, 0, 50);
+        cache.insert(1, 1, 50);
+        for key in 2..9 {
+            cache.insert(key, key, 100);
+        }
+
+        cache.insert(9, 9, 100);
+        assert_eq!(cache.get(&0), None);
+        assert_eq!(cache.get(&1), None);
+        let stats = cache.stats();
+        assert_eq!((stats.entries, stats.bytes), (8, 800));
+    }
+}
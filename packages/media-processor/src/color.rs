@@ -0,0 +1,277 @@
+//! 埋め込み ICC プロファイルに基づく色空間変換。
+//!
+//! メタデータ削除で ICC プロファイルも失われるため、Display P3 や Adobe RGB の画像は
+//! ピクセル値を出力の色空間（デフォルトは sRGB）に変換してからエンコードする。
+//! sRGB 以外の出力にはその色空間のプロファイルを付ける（プロファイルのない画像は sRGB として
+//! 表示されるため、sRGB の出力には付けない）。
+
+use std::sync::OnceLock;
+
+use image::{DynamicImage, ImageBuffer, Pixel};
+use moxcms::{ColorProfile, DataColorSpace, Layout, TransformOptions};
+
+/// `cs` パラメータで指定する出力の色空間。
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ColorSpace {
+    /// sRGB に変換する
+    #[default]
+    Srgb,
+    /// Display P3 に変換する（広色域ディスプレイ向け）
+    DisplayP3,
+    /// 原本の色空間のまま出力し、原本のプロファイルを埋め込む
+    Keep,
+}
+
+impl ColorSpace {
+    pub fn from_str_param(s: &str) -> Option<Self> {
+        match s.to_lowercase().as_str() {
+            "srgb" => Some(Self::Srgb),
+            "p3" | "display-p3" => Some(Self::DisplayP3),
+            "keep" => Some(Self::Keep),
+            _ => None,
+        }
+    }
+}
+
+/// 原本の色空間。
+pub struct SourceProfile {
+    profile: ColorProfile,
+    /// `cs=keep` で出力に埋め込むプロファイル（原本のバイト列、nclx の場合はエンコードしたもの）
+    icc: Vec<u8>,
+}
+
+/// 出力画像の色空間。エンコード時にプロファイルとして埋め込む。
+#[derive(Debug, Clone, PartialEq)]
+pub enum OutputColor {
+    Srgb,
+    DisplayP3,
+    /// 原本から引き継いだ ICC プロファイル
+    Icc(Vec<u8>),
+}
+
+impl OutputColor {
+    /// 出力に埋め込む ICC プロファイル。sRGB は埋め込まない。
+    pub fn icc(&self) -> Option<&[u8]> {
+        match self {
+            Self::Srgb => None,
+            Self::DisplayP3 => Some(display_p3_icc()),
+            Self::Icc(icc) => Some(icc),
+        }
+    }
+}
+
+fn display_p3_icc() -> &'static [u8] {
+    static ICC: OnceLock<Vec<u8>> = OnceLock::new();
+    ICC.get_or_init(|| encode_builtin(ColorProfile::new_display_p3()))
+}
+
+fn encode_builtin(profile: ColorProfile) -> Vec<u8> {
+    profile
+        .encode()
+        .expect("built-in color profile must be encodable")
+}
+
+/// ICC プロファイルのバイト列をパースする。壊れたプロファイルは無視する（変換しない）。
+pub fn profile_from_icc(icc: &[u8]) -> Option<SourceProfile> {
+    match ColorProfile::new_from_slice(icc) {
+        Ok(profile) => Some(SourceProfile {
+            profile,
+            icc: icc.to_vec(),
+        }),
+        Err(e) => {
+            tracing::warn!(error = %e, "ignoring invalid embedded ICC profile");
+            None
+        }
+    }
+}
+
+/// ICC プロファイルを sRGB として扱えるか（原色が sRGB と一致するか）。
+///
+/// 壊れたプロファイル・RGB 以外のプロファイルは変換に使わないため、sRGB として扱う。
+/// 原色の XYZ はプロファイルの作成元によって僅かに異なるため、誤差を許容する。
+pub fn is_srgb_icc(icc: &[u8]) -> bool {
+    ColorProfile::new_from_slice(icc).map_or(true, |profile| is_srgb_profile(&profile))
+}
+
+fn is_srgb_profile(profile: &ColorProfile) -> bool {
+    const TOLERANCE: f64 = 0.005;
+
+    if profile.color_space != DataColorSpace::Rgb {
+        return true;
+    }
+    let srgb = ColorProfile::new_srgb();
+    [
+        (profile.red_colorant, srgb.red_colorant),
+        (profile.green_colorant, srgb.green_colorant),
+        (profile.blue_colorant, srgb.blue_colorant),
+    ]
+    .iter()
+    .all(|(a, b)| {
+        (a.x - b.x).abs() < TOLERANCE
+            && (a.y - b.y).abs() < TOLERANCE
+            && (a.z - b.z).abs() < TOLERANCE
+    })
+}
+
+/// HEIF/AVIF の nclx（CICP）から色空間を求める。sRGB 相当・未指定・HDR（PQ/HLG）は None。
+pub fn profile_from_cicp(
+    color_primaries: u16,
+    transfer_characteristics: u16,
+) -> Option<SourceProfile> {
+    // PQ (16) / HLG (18) はトーンマッピングが必要なため対象外
+    if matches!(transfer_characteristics, 16 | 18) {
+        return None;
+    }
+    let profile = match color_primaries {
+        9 => ColorProfile::new_bt2020(),
+        12 => ColorProfile::new_display_p3(),
+        _ => return None,
+    };
+    let icc = profile.encode().ok()?;
+    Some(SourceProfile { profile, icc })
+}
+
+/// 画像を `target` の色空間に変換し、出力に付ける色空間を返す。
+///
+/// プロファイルを持たない原本・sRGB のプロファイルを持つ原本は sRGB とみなし、ピクセルを
+/// 変換しない。AVIF は ICC プロファイルを埋め込めない（nclx のみ）ため、`cs=keep` では
+/// Display P3 に変換する。
+pub fn prepare_output(
+    img: DynamicImage,
+    source: Option<SourceProfile>,
+    target: ColorSpace,
+    avif: bool,
+) -> (DynamicImage, OutputColor) {
+    // CMYK・グレースケールのプロファイルはデコード後の RGB ピクセルに対応しないため使わない
+    // （is_srgb_profile は RGB 以外を sRGB として扱う）
+    let source = source.filter(|s| !is_srgb_profile(&s.profile));
+
+    match (target, source) {
+        (ColorSpace::Srgb, None) => (img, OutputColor::Srgb),
+        (ColorSpace::Srgb, Some(source)) => (
+            convert(img, &source.profile, &ColorProfile::new_srgb()),
+            OutputColor::Srgb,
+        ),
+        (ColorSpace::Keep, None) => (img, OutputColor::Srgb),
+        (ColorSpace::Keep, Some(source)) if !avif => (img, OutputColor::Icc(source.icc)),
+        (ColorSpace::DisplayP3 | ColorSpace::Keep, source) => {
+            let source = source.map_or_else(ColorProfile::new_srgb, |s| s.profile);
+            (
+                convert(img, &source, &ColorProfile::new_display_p3()),
+                OutputColor::DisplayP3,
+            )
+        }
+    }
+}
+
+/// `source` のプロファイルで表現された画像を `target` に変換する。
+///
+/// グレースケール画像（無彩色は sRGB と Display P3 で同じ値）や変換に失敗した場合は元の画像を返す。
+fn convert(img: DynamicImage, source: &ColorProfile, target: &ColorProfile) -> DynamicImage {
+    let result = match img {
+        DynamicImage::ImageRgb8(buf) => {
+            transform_8bit(buf, source, target, Layout::Rgb).map(DynamicImage::ImageRgb8)
+        }
+        DynamicImage::ImageRgba8(buf) => {
+            transform_8bit(buf, source, target, Layout::Rgba).map(DynamicImage::ImageRgba8)
+        }
+        DynamicImage::ImageRgb16(buf) => {
+            transform_16bit(buf, source, target, Layout::Rgb).map(DynamicImage::ImageRgb16)
+        }
+        DynamicImage::ImageRgba16(buf) => {
+            transform_16bit(buf, source, target, Layout::Rgba).map(DynamicImage::ImageRgba16)
+        }
+        DynamicImage::ImageRgb32F(_) => {
+            return convert(DynamicImage::ImageRgb16(img.to_rgb16()), source, target);
+        }
+        DynamicImage::ImageRgba32F(_) => {
+            return convert(DynamicImage::ImageRgba16(img.to_rgba16()), source, target);
+        }
+        other => return other,
+    };
+
+    result.unwrap_or_else(|(img, e)| {
+        tracing::warn!(error = %e, "color conversion failed, keeping source pixels");
+        img
+    })
+}
+
+type ConvertResult<P> = Result<ImageBuffer<P, Vec<<P as Pixel>::Subpixel>>, (DynamicImage, String)>;
+
+fn transform_8bit<P: Pixel<Subpixel = u8>>(
+    buf: ImageBuffer<P, Vec<u8>>,
+    source: &ColorProfile,
+    target: &ColorProfile,
+    layout: Layout,
+) -> ConvertResult<P>
+where
+    DynamicImage: From<ImageBuffer<P, Vec<u8>>>,
+{
+    let converted = source
+        .create_transform_8bit(layout, target, layout, TransformOptions::default())
+        .and_then(|transform| {
+            let mut dst = vec![0u8; buf.len()];
+            transform.transform(&buf, &mut dst).map(|_| dst)
+        });
+    match converted {
+        Ok(dst) => Ok(ImageBuffer::from_raw(buf.width(), buf.height(), dst)
+            .expect("converted buffer has the same size as the source")),
+        Err(e) => Err((DynamicImage::from(buf), e.to_string())),
+    }
+}
+
+fn transform_16bit<P: Pixel<Subpixel = u16>>(
+    buf: ImageBuffer<P, Vec<u16>>,
+    source: &ColorProfile,
+    target: &ColorProfile,
+    layout: Layout,
+) -> ConvertResult<P>
+where
+    DynamicImage: From<ImageBuffer<P, Vec<u16>>>,
+{
+    let converted = source
+        .create_transform_16bit(layout, target, layout, TransformOptions::default())
+        .and_then(|transform| {
+            let mut dst = vec![0u16; buf.len()];
+            transform.transform(&buf, &mut dst).map(|_| dst)
+        });
+    match converted {
+        Ok(dst) => Ok(ImageBuffer::from_raw(buf.width(), buf.height(), dst)
+            .expect("converted buffer has the same size as the source")),
+        Err(e) => Err((DynamicImage::from(buf), e.to_string())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detects_srgb_profiles() {
+        assert!(is_srgb_icc(&encode_builtin(ColorProfile::new_srgb())));
+        assert!(!is_srgb_icc(display_p3_icc()));
+        assert!(!is_srgb_icc(&encode_builtin(ColorProfile::new_adobe_rgb())));
+        // 壊れたプロファイルは変換に使わないため sRGB として扱う
+        assert!(is_srgb_icc(b"not an icc profile"));
+    }
+
+    #[test]
+    fn keeps_pixels_of_srgb_sources() {
+        let img = DynamicImage::ImageRgb8(ImageBuffer::from_pixel(2, 2, image::Rgb([200, 40, 90])));
+        let source = profile_from_icc(&encode_builtin(ColorProfile::new_srgb()));
+        let (out, color) = prepare_output(img.clone(), source, ColorSpace::Srgb, false);
+        assert_eq!(out, img);
+        assert_eq!(color, OutputColor::Srgb);
+        assert_eq!(color.icc(), None);
+
+        // cs=keep でも sRGB の原本にはプロファイルを付けない
+        let source = profile_from_icc(&encode_builtin(ColorProfile::new_srgb()));
+        let (_, color) = prepare_output(img.clone(), source, ColorSpace::Keep, false);
+        assert_eq!(color, OutputColor::Srgb);
+
+        let source = profile_from_icc(display_p3_icc());
+        let (out, color) = prepare_output(img.clone(), source, ColorSpace::Srgb, false);
+        assert_ne!(out, img);
+        assert_eq!(color, OutputColor::Srgb);
+    }
+}
@@ -0,0 +1,23 @@
+//! 環境変数から読み込む数値設定のヘルパー。
+
+/// 環境変数を usize として読み込む。未設定ならデフォルト値、不正な値なら警告してデフォルト値を使う。
+pub fn env_usize(name: &str, default: usize) -> usize {
+    match std::env::var(name) {
+        Ok(value) => value.parse().unwrap_or_else(|e| {
+            tracing::warn!("Invalid {} value, using default {}: {}", name, default, e);
+            default
+        }),
+        Err(_) => default,
+    }
+}
+
+/// 環境変数を bool（`true` / `false`）として読み込む。未設定・不正な値の扱いは `env_usize` と同じ。
+pub fn env_bool(name: &str, default: bool) -> bool {
+    match std::env::var(name) {
+        Ok(value) => value.to_lowercase().parse().unwrap_or_else(|e| {
+            tracing::warn!("Invalid {} value, using default {}: {}", name, default, e);
+            default
+        }),
+        Err(_) => default,
+    }
+}
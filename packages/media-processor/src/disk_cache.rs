@@ -0,0 +1,334 @@
+//! 変換結果のディスクキャッシュ（任意）。
+//!
+//! 長期間動かすセルフホストのインスタンスが再起動しても変換をやり直さないよう、変換結果を
+//! ディレクトリに保存する（Cloud Run ではメモリ上の tmpfs になる）。ファイル名はオブジェクトキーと
+//! パース済みパラメータのハッシュ。原本はアップロードごとに新しいキー（UUID）で保存され、同じキーの
+//! 内容は変わらないため、原本の ETag は含めない。書き込みは一時ファイルからの rename で行い、
+//! 書きかけのファイルを読まないようにする。容量を超えたら最も古く使われた（更新日時が古い）ものから削除する。
+//!
+//! DISK_CACHE_DIR が他の用途と共有されていても他のファイルを消さないよう、キャッシュは専用の
+//! サブディレクトリに置き、キャッシュのファイル名の形式に合うものだけを管理対象にする。
+
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use bytes::Bytes;
+use lru::LruCache;
+
+use crate::cache::{CacheStats, key_digest};
+use crate::config::env_usize;
+use crate::fs::write_atomic;
+use crate::transform::OutputFormat;
+
+/// キャッシュを置くサブディレクトリ（ファイルの形式を変えたら番号を上げる）。
+const CACHE_DIR: &str = "v1";
+/// 書き込み中の一時ファイルを置くサブディレクトリ（CACHE_DIR 内）。
+const TMP_DIR: &str = "tmp";
+/// ファイル名（SHA-256 の 16 進数）の長さと、シャードのサブディレクトリ名の長さ。
+const NAME_LEN: usize = 64;
+const SHARD_LEN: usize = 2;
+
+#[derive(Clone)]
+pub struct DiskCache {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    dir: PathBuf,
+    capacity_bytes: u64,
+    /// ファイル名 → サイズ（最近使った順）
+    index: Mutex<Index>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+struct Index {
+    entries: LruCache<String, u64>,
+    bytes: u64,
+}
+
+impl DiskCache {
+    /// 環境変数から DiskCache を作成する。ディレクトリが未設定、または使えない場合は None。
+    ///
+    /// 任意の環境変数:
+    /// - DISK_CACHE_DIR: キャッシュを置くディレクトリ（未設定ならディスクキャッシュを使わない）
+    /// - DISK_CACHE_MB: キャッシュの容量（デフォルト: 1024）
+    pub fn from_env() -> Option<Self> {
+        let dir = std::env::var("DISK_CACHE_DIR")
+            .ok()
+            .filter(|dir| !dir.is_empty())?;
+        let capacity_mb = env_usize("DISK_CACHE_MB", 1024);
+
+        match Self::open(PathBuf::from(&dir), capacity_mb as u64 * 1024 * 1024) {
+            Ok(cache) => {
+                let entries = cache.lock().entries.len();
+                tracing::info!(dir, capacity_mb, entries, "disk cache configured");
+                Some(cache)
+            }
+            Err(e) => {
+                tracing::warn!(dir, error = %e, "failed to open disk cache, disabling it");
+                None
+            }
+        }
+    }
+
+    /// ディレクトリ内の既存のキャッシュを、更新日時の古い順に使用履歴として読み込む。
+    pub fn open(dir: PathBuf, capacity_bytes: u64) -> std::io::Result<Self> {
+        let dir = dir.join(CACHE_DIR);
+        let tmp_dir = dir.join(TMP_DIR);
+        std::fs::create_dir_all(&tmp_dir)?;
+        // 書き込み途中で終了した一時ファイルは使えないため削除する
+        for entry in std::fs::read_dir(&tmp_dir)? {
+            let _ = std::fs::remove_file(entry?.path());
+        }
+
+        let mut files = Vec::new();
+        for shard in std::fs::read_dir(&dir)? {
+            let shard = shard?;
+            let Ok(shard_name) = shard.file_name().into_string() else {
+                continue;
+            };
+            if !is_hex(&shard_name, SHARD_LEN) || !shard.file_type()?.is_dir() {
+                continue;
+            }
+            for file in std::fs::read_dir(shard.path())? {
+                let file = file?;
+                let Ok(name) = file.file_name().into_string() else {
+                    continue;
+                };
+                // シャードと一致しないものは自分が書いたファイルではないため触らない
+                if !is_hex(&name, NAME_LEN) || !name.starts_with(&shard_name) {
+                    continue;
+                }
+                let metadata = file.metadata()?;
+                if metadata.is_file() {
+                    let modified = metadata.modified().unwrap_or(UNIX_EPOCH);
+                    files.push((modified, name, metadata.len()));
+                }
+            }
+        }
+        files.sort();
+
+        let mut index = Index {
+            entries: LruCache::unbounded(),
+            bytes: 0,
+        };
+        for (_, name, size) in files {
+            index.entries.put(name, size);
+            index.bytes += size;
+        }
+
+        let cache = Self {
+            inner: Arc::new(Inner {
+                dir,
+                capacity_bytes,
+                index: Mutex::new(index),
+                hits: AtomicU64::new(0),
+                misses: AtomicU64::new(0),
+            }),
+        };
+        // 前回より容量を減らして起動した場合に備え、超過分を削除する
+        for path in cache.evict() {
+            let _ = std::fs::remove_file(path);
+        }
+        Ok(cache)
+    }
+
+    /// キャッシュ済みの変換結果 (バイト列, content_type) を読み込む。
+    pub async fn get(&self, cache_key: &str) -> Option<(Bytes, &'static str)> {
+        let name = key_digest(cache_key);
+        let known = self.lock().entries.get(&name).is_some();
+        let output = if known { self.read(&name).await } else { None };
+
+        let counter = if output.is_some() {
+            &self.inner.hits
+        } else {
+            &self.inner.misses
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        output
+    }
+
+    /// 変換結果を書き込む。失敗しても変換結果の返却には影響しないため、ログだけ残す。
+    pub async fn insert(&self, cache_key: &str, output: &(Bytes, &'static str)) {
+        let name = key_digest(cache_key);
+        let (data, content_type) = output;
+        let size = (content_type.len() + 1 + data.len()) as u64;
+        if size > self.inner.capacity_bytes {
+            return;
+        }
+
+        if let Err(e) = self.write(&name, data, content_type).await {
+            tracing::warn!(error = %e, "failed to write disk cache entry");
+            return;
+        }
+
+        let evicted = {
+            let mut index = self.lock();
+            if let Some(old_size) = index.entries.put(name, size) {
+                index.bytes -= old_size;
+            }
+            index.bytes += size;
+            drop(index);
+            self.evict()
+        };
+        for path in evicted {
+            let _ = tokio::fs::remove_file(path).await;
+        }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let index = self.lock();
+        CacheStats {
+            hits: self.inner.hits.load(Ordering::Relaxed),
+            misses: self.inner.misses.load(Ordering::Relaxed),
+            entries: index.entries.len(),
+            bytes: index.bytes as usize,
+            capacity_bytes: self.inner.capacity_bytes as usize,
+        }
+    }
+
+    /// ファイルは `<content_type>\n<本文>` の形式で保存する。
+    async fn read(&self, name: &str) -> Option<(Bytes, &'static str)> {
+        let path = self.path(name);
+        let parsed = tokio::fs::read(&path).await.ok().and_then(|data| {
+            let newline = data.iter().position(|&b| b == b'\n')?;
+            let content_type = std::str::from_utf8(&data[..newline]).ok()?;
+            let format = OutputFormat::from_content_type(content_type)?;
+            Some((
+                Bytes::from(data).slice(newline + 1..),
+                format.content_type(),
+            ))
+        });
+
+        match parsed {
+            Some(output) => {
+                // 更新日時を最終アクセスとして使い、再起動後も使用履歴を引き継ぐ
+                touch(&path).await;
+                Some(output)
+            }
+            None => {
+                // 削除済み・破損したファイルは索引から外す
+                let mut index = self.lock();
+                if let Some(size) = index.entries.pop(name) {
+                    index.bytes -= size;
+                }
+                None
+            }
+        }
+    }
+
+    async fn write(&self, name: &str, data: &[u8], content_type: &str) -> std::io::Result<()> {
+        let mut contents = Vec::with_capacity(content_type.len() + 1 + data.len());
+        contents.extend_from_slice(content_type.as_bytes());
+        contents.push(b'\n');
+        contents.extend_from_slice(data);
+        write_atomic(&self.path(name), &self.inner.dir.join(TMP_DIR), &contents).await
+    }
+
+    /// 容量を超えた分を索引から外し、削除するファイルのパスを返す。
+    fn evict(&self) -> Vec<PathBuf> {
+        let mut index = self.lock();
+        let mut evicted = Vec::new();
+        while index.bytes > self.inner.capacity_bytes {
+            let Some((name, size)) = index.entries.pop_lru() else {
+                break;
+            };
+            index.bytes -= size;
+            evicted.push(self.path(&name));
+        }
+        evicted
+    }
+
+    /// 先頭 2 文字のサブディレクトリに分け、1 つのディレクトリにファイルが集中しないようにする。
+    /// `name` は `key_digest` の結果か、`open` で形式を確認したファイル名のみ。
+    fn path(&self, name: &str) -> PathBuf {
+        self.inner.dir.join(&name[..SHARD_LEN]).join(name)
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Index> {
+        crate::sync::lock(&self.inner.index)
+    }
+}
+
+/// `len` 文字の小文字の 16 進数か（`key_digest` の出力形式）。
+fn is_hex(name: &str, len: usize) -> bool {
+    name.len() == len && name.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+async fn touch(path: &Path) {
+    let Ok(file) = tokio::fs::File::options().write(true).open(path).await else {
+        return;
+    };
+    let _ = file.into_std().await.set_modified(SystemTime::now());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("disk-cache-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn output(len: usize) -> (Bytes, &'static str) {
+        (Bytes::from(vec![0u8; len]), "image/webp")
+    }
+
+    #[tokio::test]
+    async fn evicts_least_recently_used_and_restores_index_on_open() {
+        let dir = temp_dir("lru");
+        let cache = DiskCache::open(dir.clone(), 100).unwrap();
+        cache.insert("a", &output(30)).await;
+        cache.insert("b", &output(30)).await;
+        assert_eq!(cache.get("a").await, Some(output(30)));
+        // "image/webp\n" の 11 バイトを含め 41 バイトずつなので、3 つ目で最も古い b を捨てる
+        cache.insert("c", &output(30)).await;
+        assert_eq!(cache.get("b").await, None);
+        assert_eq!(cache.stats().entries, 2);
+
+        let reopened = DiskCache::open(dir.clone(), 100).unwrap();
+        assert_eq!(reopened.stats().entries, 2);
+        assert_eq!(reopened.get("c").await, Some(output(30)));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn ignores_files_it_does_not_own() {
+        let dir = temp_dir("foreign");
+        let digest = key_digest("a");
+        let foreign = [
+            dir.join("unrelated.txt"),
+            dir.join("ab").join("x"),
+            dir.join(CACHE_DIR).join("notes").join("readme"),
+            dir.join(CACHE_DIR).join("zz").join(&digest),
+            dir.join(CACHE_DIR)
+                .join(&digest[..SHARD_LEN])
+                .join(digest.to_uppercase()),
+        ];
+        for path in &foreign {
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, vec![0u8; 1000]).unwrap();
+        }
+
+        // 容量を超える他のファイルがあっても索引に入れず、削除もしない
+        let cache = DiskCache::open(dir.clone(), 100).unwrap();
+        assert_eq!(cache.stats().entries, 0);
+        cache.insert("a", &output(30)).await;
+        cache.insert("b", &output(30)).await;
+        cache.insert("c", &output(30)).await;
+        drop(cache);
+        let cache = DiskCache::open(dir.clone(), 10).unwrap();
+        assert_eq!(cache.stats().entries, 0);
+        for path in &foreign {
+            assert!(path.exists(), "{} was removed", path.display());
+        }
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,170 @@
+//! filter / sharpen によるリサイズフィルタの選択と、縮小後の鮮鋭化。
+
+use fast_image_resize::{FilterType, ResizeAlg};
+use image::{DynamicImage, ImageBuffer, Pixel, Primitive};
+use num_traits::NumCast;
+
+/// sharpen に指定できる最大の強さ。
+pub const MAX_SHARPEN: f32 = 10.0;
+/// アンシャープマスクのぼかしの標準偏差（出力のピクセル単位）。
+const SHARPEN_SIGMA: f32 = 1.0;
+
+/// リサイズの補間フィルタ。
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ResizeFilter {
+    /// 最近傍（ドット絵のスキャン等、ピクセルの境界をぼかさない）
+    Nearest,
+    /// 双線形（高速なプレビュー用）
+    Bilinear,
+    CatmullRom,
+    Mitchell,
+    #[default]
+    Lanczos3,
+    /// 面積平均
+    Box,
+}
+
+impl ResizeFilter {
+    pub fn from_str_param(s: &str) -> Option<Self> {
+        match s.to_lowercase().as_str() {
+            "nearest" => Some(Self::Nearest),
+            "bilinear" => Some(Self::Bilinear),
+            "catmull-rom" => Some(Self::CatmullRom),
+            "mitchell" => Some(Self::Mitchell),
+            "lanczos3" => Some(Self::Lanczos3),
+            "box" => Some(Self::Box),
+            _ => None,
+        }
+    }
+
+    /// filter 未指定時のフィルタ。拡大では Lanczos3 の輪郭の縞（リンギング）が目立つため、
+    /// 滑らかに補間する Mitchell を使う。
+    pub fn default_for(upscale: bool) -> Self {
+        if upscale {
+            Self::Mitchell
+        } else {
+            Self::default()
+        }
+    }
+
+    pub fn resize_alg(self) -> ResizeAlg {
+        match self {
+            Self::Nearest => ResizeAlg::Nearest,
+            Self::Bilinear => ResizeAlg::Convolution(FilterType::Bilinear),
+            Self::CatmullRom => ResizeAlg::Convolution(FilterType::CatmullRom),
+            Self::Mitchell => ResizeAlg::Convolution(FilterType::Mitchell),
+            Self::Lanczos3 => ResizeAlg::Convolution(FilterType::Lanczos3),
+            Self::Box => ResizeAlg::Convolution(FilterType::Box),
+        }
+    }
+}
+
+/// アンシャープマスクで画像を鮮鋭化する。
+///
+/// ぼかした画像との差分を `amount` 倍して元の画像に足す（0 なら何もしない）。
+/// アルファは鮮鋭化せず、透明部分の輪郭が欠けたり縁取られたりしないようにする。
+pub fn sharpen(img: DynamicImage, amount: f32) -> DynamicImage {
+    if amount <= 0.0 {
+        return img;
+    }
+
+    // blur は 16bit の画像でカーネルの丸め誤差により平坦な部分の値もずれるため、
+    // ボックスブラーの繰り返しでガウスぼかしを近似する fast_blur を使う
+    let blurred = img.fast_blur(SHARPEN_SIGMA);
+    match (img, blurred) {
+        (DynamicImage::ImageLuma8(img), DynamicImage::ImageLuma8(blurred)) => {
+            DynamicImage::ImageLuma8(unsharp_mask(img, &blurred, amount))
+        }
+        (DynamicImage::ImageLumaA8(img), DynamicImage::ImageLumaA8(blurred)) => {
+            DynamicImage::ImageLumaA8(unsharp_mask(img, &blurred, amount))
+        }
+        (DynamicImage::ImageRgb8(img), DynamicImage::ImageRgb8(blurred)) => {
+            DynamicImage::ImageRgb8(unsharp_mask(img, &blurred, amount))
+        }
+        (DynamicImage::ImageRgba8(img), DynamicImage::ImageRgba8(blurred)) => {
+            DynamicImage::ImageRgba8(unsharp_mask(img, &blurred, amount))
+        }
+        (DynamicImage::ImageLuma16(img), DynamicImage::ImageLuma16(blurred)) => {
+            DynamicImage::ImageLuma16(unsharp_mask(img, &blurred, amount))
+        }
+        (DynamicImage::ImageLumaA16(img), DynamicImage::ImageLumaA16(blurred)) => {
+            DynamicImage::ImageLumaA16(unsharp_mask(img, &blurred, amount))
+        }
+        (DynamicImage::ImageRgb16(img), DynamicImage::ImageRgb16(blurred)) => {
+            DynamicImage::ImageRgb16(unsharp_mask(img, &blurred, amount))
+        }
+        (DynamicImage::ImageRgba16(img), DynamicImage::ImageRgba16(blurred)) => {
+            DynamicImage::ImageRgba16(unsharp_mask(img, &blurred, amount))
+        }
+        (DynamicImage::ImageRgb32F(img), DynamicImage::ImageRgb32F(blurred)) => {
+            DynamicImage::ImageRgb32F(unsharp_mask(img, &blurred, amount))
+        }
+        (DynamicImage::ImageRgba32F(img), DynamicImage::ImageRgba32F(blurred)) => {
+            DynamicImage::ImageRgba32F(unsharp_mask(img, &blurred, amount))
+        }
+        // blur はピクセル形式を変えないため、ここに来るのは image クレートの将来の拡張のみ
+        (img, _) => img,
+    }
+}
+
+fn unsharp_mask<P: Pixel>(
+    mut img: ImageBuffer<P, Vec<P::Subpixel>>,
+    blurred: &ImageBuffer<P, Vec<P::Subpixel>>,
+    amount: f32,
+) -> ImageBuffer<P, Vec<P::Subpixel>> {
+    let channels = P::CHANNEL_COUNT as usize;
+    let alpha_index = P::HAS_ALPHA.then(|| channels - 1);
+    let min = to_f32(P::Subpixel::DEFAULT_MIN_VALUE);
+    let max = to_f32(P::Subpixel::DEFAULT_MAX_VALUE);
+    // 浮動小数点の形式（最大値 1.0）は 1.0 を超える値を持ちうるため、丸め・切り詰めは整数の形式のみ
+    let integer = max > 1.0;
+
+    for (i, (value, &blurred)) in img.iter_mut().zip(blurred.iter()).enumerate() {
+        if Some(i % channels) == alpha_index {
+            continue;
+        }
+        let original = to_f32(*value);
+        let sharpened = original + (original - to_f32(blurred)) * amount;
+        let sharpened = if integer {
+            sharpened.clamp(min, max).round()
+        } else {
+            sharpened
+        };
+        *value = NumCast::from(sharpened).unwrap_or(*value);
+    }
+    img
+}
+
+fn to_f32<S: Primitive>(value: S) -> f32 {
+    NumCast::from(value).unwrap_or(0.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{ImageBuffer, Rgb, Rgba};
+
+    #[test]
+    fn sharpen_leaves_flat_images_unchanged() {
+        let flat = DynamicImage::ImageRgb8(ImageBuffer::from_pixel(16, 16, Rgb([90, 140, 200])));
+        assert_eq!(sharpen(flat.clone(), 1.0), flat);
+
+        let flat = DynamicImage::ImageRgba16(ImageBuffer::from_pixel(
+            16,
+            16,
+            Rgba([1000, 30000, 65000, 40000]),
+        ));
+        assert_eq!(sharpen(flat.clone(), 1.0), flat);
+    }
+
+    #[test]
+    fn sharpen_increases_contrast_at_edges() {
+        let edge = DynamicImage::ImageRgb8(ImageBuffer::from_fn(16, 16, |x, _| {
+            Rgb([if x < 8 { 100 } else { 150 }; 3])
+        }));
+        let sharpened = sharpen(edge.clone(), 1.0).to_rgb8();
+        assert!(sharpened.get_pixel(7, 8)[0] < 100);
+        assert!(sharpened.get_pixel(8, 8)[0] > 150);
+        assert_eq!(sharpen(edge.clone(), 0.0), edge);
+    }
+}
@@ -0,0 +1,56 @@
+//! ローカルのファイルへの書き込み（ローカルのストレージとディスクキャッシュで共有する）。
+
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// 一時ファイル名の重複を避ける連番（プロセス内で共有する）。
+static TMP_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// `tmp_dir` 内の一時ファイルに書き込んでから `path` に rename し、書きかけのファイルを読まないようにする。
+///
+/// 親ディレクトリがなければ作る。失敗した場合は一時ファイルを削除する。
+pub async fn write_atomic(path: &Path, tmp_dir: &Path, contents: &[u8]) -> std::io::Result<()> {
+    let tmp_path = tmp_dir.join(format!(
+        "{}.{}",
+        std::process::id(),
+        TMP_COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+
+    let result = async {
+        tokio::fs::create_dir_all(tmp_dir).await?;
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        tokio::fs::write(&tmp_path, contents).await?;
+        tokio::fs::rename(&tmp_path, path).await
+    }
+    .await;
+    if result.is_err() {
+        let _ = tokio::fs::remove_file(&tmp_path).await;
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn replaces_files_without_leaving_temporary_files() {
+        let root = std::env::temp_dir().join(format!("write-atomic-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        let path = root.join("a/b/file");
+        let tmp_dir = root.join("tmp");
+
+        write_atomic(&path, &tmp_dir, b"first").await.unwrap();
+        write_atomic(&path, &tmp_dir, b"second").await.unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"second");
+        assert_eq!(std::fs::read_dir(&tmp_dir).unwrap().count(), 0);
+
+        // 書き込み先がディレクトリの場合は rename に失敗し、一時ファイルも残さない
+        assert!(write_atomic(&root.join("a"), &tmp_dir, b"x").await.is_err());
+        assert_eq!(std::fs::read_dir(&tmp_dir).unwrap().count(), 0);
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
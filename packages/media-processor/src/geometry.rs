@@ -0,0 +1,646 @@
+//! リサイズ後のサイズ・切り取り領域・余白の計算。
+
+/// 指定矩形 (w × h) に対する画像の収め方。
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Fit {
+    /// アスペクト比を維持して矩形内に収める（余白なし）
+    #[default]
+    Contain,
+    /// Contain と同じ（sharp の fit 名との互換用）
+    Inside,
+    /// アスペクト比を維持して矩形を覆うように拡縮し、はみ出た部分を gravity に従って切り取る
+    Cover,
+    /// アスペクト比を無視して矩形に合わせる
+    Fill,
+    /// アスペクト比を維持して矩形を覆う最小サイズに拡縮する（切り取りなし）
+    Outside,
+    /// Contain で収めた上で、余白を埋めて矩形サイズちょうどにする
+    Pad,
+}
+
+impl Fit {
+    pub fn from_str_param(s: &str) -> Option<Self> {
+        match s.to_lowercase().as_str() {
+            "contain" => Some(Self::Contain),
+            "inside" => Some(Self::Inside),
+            "cover" => Some(Self::Cover),
+            "fill" => Some(Self::Fill),
+            "outside" => Some(Self::Outside),
+            "pad" => Some(Self::Pad),
+            _ => None,
+        }
+    }
+}
+
+/// Cover での切り取り位置、Pad での画像の配置位置。
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Gravity {
+    #[default]
+    Center,
+    North,
+    South,
+    East,
+    West,
+    NorthEast,
+    NorthWest,
+    SouthEast,
+    SouthWest,
+    /// 焦点（画像に対する 0.0〜1.0 の相対座標）。可能な限り焦点を中心に置く
+    Focal {
+        x: f64,
+        y: f64,
+    },
+    /// 画像内容（エッジ・彩度・肌色）から切り取り位置を推定する。
+    /// 位置は transform 側で決めるため、ここでは Center として扱う
+    Smart,
+}
+
+impl Gravity {
+    pub fn from_str_param(s: &str) -> Option<Self> {
+        match s.to_lowercase().as_str() {
+            "center" | "centre" => Some(Self::Center),
+            "north" => Some(Self::North),
+            "south" => Some(Self::South),
+            "east" => Some(Self::East),
+            "west" => Some(Self::West),
+            "northeast" => Some(Self::NorthEast),
+            "northwest" => Some(Self::NorthWest),
+            "southeast" => Some(Self::SouthEast),
+            "southwest" => Some(Self::SouthWest),
+            "smart" => Some(Self::Smart),
+            _ => None,
+        }
+    }
+
+    /// 大きさ `outer` の領域内に大きさ `inner` の矩形を配置したときの左上座標を返す。
+    fn place(self, outer: (f64, f64), inner: (f64, f64)) -> (f64, f64) {
+        let (free_w, free_h) = ((outer.0 - inner.0).max(0.0), (outer.1 - inner.1).max(0.0));
+
+        let (ax, ay) = match self {
+            Self::Center | Self::Smart => (0.5, 0.5),
+            Self::North => (0.5, 0.0),
+            Self::South => (0.5, 1.0),
+            Self::East => (1.0, 0.5),
+            Self::West => (0.0, 0.5),
+            Self::NorthEast => (1.0, 0.0),
+            Self::NorthWest => (0.0, 0.0),
+            Self::SouthEast => (1.0, 1.0),
+            Self::SouthWest => (0.0, 1.0),
+            Self::Focal { x, y } => {
+                // 焦点を矩形の中心に置き、はみ出す場合は端に寄せる
+                let left = (x * outer.0 - inner.0 / 2.0).clamp(0.0, free_w);
+                let top = (y * outer.1 - inner.1 / 2.0).clamp(0.0, free_h);
+                return (left, top);
+            }
+        };
+
+        (free_w * ax, free_h * ay)
+    }
+}
+
+/// ソース画像上の矩形領域（ピクセル単位、サブピクセル精度）。
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Region {
+    pub left: f64,
+    pub top: f64,
+    pub width: f64,
+    pub height: f64,
+}
+
+impl Region {
+    pub fn full(width: u32, height: u32) -> Self {
+        Self {
+            left: 0.0,
+            top: 0.0,
+            width: width as f64,
+            height: height as f64,
+        }
+    }
+
+    pub fn is_full(&self, width: u32, height: u32) -> bool {
+        *self == Self::full(width, height)
+    }
+}
+
+/// 指定されたリサイズの目標。
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ResizeTarget {
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub fit: Fit,
+    pub gravity: Gravity,
+    /// 出力のアスペクト比（幅 / 高さ）
+    pub aspect_ratio: Option<f64>,
+    /// 元画像より大きいサイズへの拡大を許可する（未指定時は withoutEnlargement）
+    pub enlarge: bool,
+    /// 拡大時に出力の一辺が超えないようにする上限（px）
+    pub max_dimension: u32,
+}
+
+/// Pad で使うキャンバス。リサイズ後の画像を (x, y) に配置する。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Canvas {
+    pub width: u32,
+    pub height: u32,
+    pub x: u32,
+    pub y: u32,
+}
+
+/// リサイズ計画。`source` の領域を `width` × `height` にリサイズし、`canvas` があれば余白を付ける。
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ResizePlan {
+    pub source: Region,
+    pub width: u32,
+    pub height: u32,
+    pub canvas: Option<Canvas>,
+}
+
+impl ResizePlan {
+    /// 縦横どちらかが元の領域より大きくなるか。
+    pub fn is_upscale(&self) -> bool {
+        self.width as f64 > self.source.width || self.height as f64 > self.source.height
+    }
+
+    /// 最終的な出力画像のサイズ。
+    pub fn output_dimensions(&self) -> (u32, u32) {
+        match self.canvas {
+            Some(canvas) => (canvas.width, canvas.height),
+            None => (self.width, self.height),
+        }
+    }
+}
+
+/// 切り取り矩形の各成分（ピクセル値または画像サイズに対する百分率）。
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CropLength {
+    Pixels(u32),
+    Percent(f64),
+}
+
+impl CropLength {
+    fn parse(s: &str) -> Option<Self> {
+        let s = s.trim();
+        match s.strip_suffix('%') {
+            Some(pct) => {
+                let pct: f64 = pct.trim().parse().ok()?;
+                (0.0..=100.0).contains(&pct).then_some(Self::Percent(pct))
+            }
+            None => s.parse().ok().map(Self::Pixels),
+        }
+    }
+
+    fn resolve(self, extent: u32) -> u32 {
+        match self {
+            Self::Pixels(px) => px,
+            Self::Percent(pct) => (extent as f64 * pct / 100.0).round() as u32,
+        }
+    }
+}
+
+/// `crop=x,y,w,h` で指定する切り取り矩形（向き補正後の画像基準）。
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CropRect {
+    pub x: CropLength,
+    pub y: CropLength,
+    pub width: CropLength,
+    pub height: CropLength,
+}
+
+impl CropRect {
+    /// `x,y,w,h` 形式をパースする。各成分は `120` のようなピクセル値か `25%` のような百分率。
+    pub fn parse(s: &str) -> Option<Self> {
+        let parts: Vec<_> = s.split(',').map(CropLength::parse).collect::<Option<_>>()?;
+        let [x, y, width, height] = parts[..] else {
+            return None;
+        };
+        Some(Self {
+            x,
+            y,
+            width,
+            height,
+        })
+    }
+
+    /// 画像サイズに対するピクセル単位の矩形 (x, y, w, h) を返す。
+    /// 矩形が空、または画像からはみ出す場合は None。
+    pub fn resolve(&self, src_w: u32, src_h: u32) -> Option<(u32, u32, u32, u32)> {
+        let x = self.x.resolve(src_w);
+        let y = self.y.resolve(src_h);
+        let right = Self::end(x, self.width, src_w)?;
+        let bottom = Self::end(y, self.height, src_h)?;
+
+        (right > x && bottom > y && right <= src_w && bottom <= src_h)
+            .then(|| (x, y, right - x, bottom - y))
+    }
+
+    /// 始点 `start` と長さ `length` から終端座標を求める。
+    /// 百分率は丸め誤差で 1px はみ出すことがあるため、その範囲は画像端に揃える。
+    fn end(start: u32, length: CropLength, extent: u32) -> Option<u32> {
+        match length {
+            CropLength::Pixels(px) => start.checked_add(px),
+            CropLength::Percent(pct) => {
+                let end = (start as f64 + extent as f64 * pct / 100.0).round() as u32;
+                if end > extent + 1 {
+                    return None;
+                }
+                Some(end.min(extent))
+            }
+        }
+    }
+}
+
+/// `ar=16:9` 形式のアスペクト比（幅 / 高さ）をパースする。
+pub fn parse_aspect_ratio(s: &str) -> Option<f64> {
+    let (w, h) = s.split_once(':')?;
+    let w: f64 = w.trim().parse().ok()?;
+    let h: f64 = h.trim().parse().ok()?;
+    (w.is_finite() && h.is_finite() && w > 0.0 && h > 0.0).then_some(w / h)
+}
+
+/// ソースサイズと指定パラメータからリサイズ計画を立てる。
+///
+/// - `enlarge` が false の場合は元画像より大きくしない（withoutEnlargement）。true の場合も
+///   出力の一辺は `max_dimension` までに抑える。
+/// - `aspect_ratio` 指定時は、w / h の片方からもう片方を算出する。どちらもなければ
+///   ソースから指定比率で切り出せる最大サイズを目標にする。
+/// - w と h が揃わない場合、fit に関わらず Contain と同じ挙動になる。
+pub fn plan_resize(src_w: u32, src_h: u32, target: &ResizeTarget) -> ResizePlan {
+    let (target_w, target_h) = match target.aspect_ratio {
+        Some(ar) => resolve_aspect_ratio(src_w, src_h, target.width, target.height, ar),
+        None => (target.width, target.height),
+    };
+
+    let (sw, sh) = (src_w as f64, src_h as f64);
+    let max_scale = if target.enlarge {
+        let max = target.max_dimension as f64;
+        (max / sw).min(max / sh).max(1.0)
+    } else {
+        1.0
+    };
+
+    let full = Region::full(src_w, src_h);
+    let contain = |target_w, target_h| {
+        let (width, height) =
+            calculate_contain_dimensions(src_w, src_h, target_w, target_h, max_scale);
+        ResizePlan {
+            source: full,
+            width,
+            height,
+            canvas: None,
+        }
+    };
+
+    let (Some(tw), Some(th)) = (target_w, target_h) else {
+        return contain(target_w, target_h);
+    };
+
+    let (twf, thf) = (tw as f64, th as f64);
+
+    match target.fit {
+        Fit::Contain | Fit::Inside => contain(Some(tw), Some(th)),
+        Fit::Cover => {
+            let scale = (twf / sw).max(thf / sh);
+            let window = (twf / scale, thf / scale);
+            let (left, top) = target.gravity.place((sw, sh), window);
+            // 拡大が必要で許可されていない場合は、比率だけ合わせた切り出し領域をそのままのサイズで返す
+            let (width, height) = if scale > 1.0 && !target.enlarge {
+                (round_dimension(window.0), round_dimension(window.1))
+            } else {
+                (tw, th)
+            };
+            ResizePlan {
+                source: Region {
+                    left,
+                    top,
+                    width: window.0,
+                    height: window.1,
+                },
+                width,
+                height,
+                canvas: None,
+            }
+        }
+        Fit::Fill => {
+            let (width, height) = if target.enlarge {
+                (tw, th)
+            } else {
+                (tw.min(src_w), th.min(src_h))
+            };
+            let (width, height) = (width.max(1), height.max(1));
+            ResizePlan {
+                source: full,
+                width,
+                height,
+                canvas: None,
+            }
+        }
+        Fit::Outside => {
+            let scale = (twf / sw).max(thf / sh).min(max_scale);
+            ResizePlan {
+                source: full,
+                width: round_dimension(sw * scale),
+                height: round_dimension(sh * scale),
+                canvas: None,
+            }
+        }
+        Fit::Pad => {
+            let plan = contain(Some(tw), Some(th));
+            let (x, y) = target
+                .gravity
+                .place((twf, thf), (plan.width as f64, plan.height as f64));
+            ResizePlan {
+                canvas: Some(Canvas {
+                    width: tw,
+                    height: th,
+                    x: x.round() as u32,
+                    y: y.round() as u32,
+                }),
+                ..plan
+            }
+        }
+    }
+}
+
+/// アスペクト比から目標サイズを補完する。
+fn resolve_aspect_ratio(
+    src_w: u32,
+    src_h: u32,
+    target_w: Option<u32>,
+    target_h: Option<u32>,
+    ar: f64,
+) -> (Option<u32>, Option<u32>) {
+    match (target_w, target_h) {
+        (Some(w), None) => (Some(w), Some(round_dimension(w as f64 / ar))),
+        (None, Some(h)) => (Some(round_dimension(h as f64 * ar)), Some(h)),
+        (None, None) => {
+            let (sw, sh) = (src_w as f64, src_h as f64);
+            if sw / sh > ar {
+                (Some(round_dimension(sh * ar)), Some(src_h))
+            } else {
+                (Some(src_w), Some(round_dimension(sw / ar)))
+            }
+        }
+        // w と h が両方指定された場合はバリデーションで弾いている
+        both => both,
+    }
+}
+
+fn round_dimension(value: f64) -> u32 {
+    (value.round() as u32).max(1)
+}
+
+/// "contain" モードで出力サイズを計算する。拡大率は `max_scale` までに抑える
+/// （1.0 なら withoutEnlargement）。
+///
+/// - w のみ: 幅に合わせて拡縮、高さは自動
+/// - h のみ: 高さに合わせて拡縮、幅は自動
+/// - 両方: バウンディングボックス内に収める
+/// - どちらもなし: 元のサイズを維持
+fn calculate_contain_dimensions(
+    src_w: u32,
+    src_h: u32,
+    target_w: Option<u32>,
+    target_h: Option<u32>,
+    max_scale: f64,
+) -> (u32, u32) {
+    let (sw, sh) = (src_w as f64, src_h as f64);
+    let scale = match (target_w, target_h) {
+        (Some(w), Some(h)) => (w as f64 / sw).min(h as f64 / sh),
+        (Some(w), None) => w as f64 / sw,
+        (None, Some(h)) => h as f64 / sh,
+        (None, None) => return (src_w, src_h),
+    };
+    let scale = scale.min(max_scale);
+    (round_dimension(sw * scale), round_dimension(sh * scale))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn target(width: Option<u32>, height: Option<u32>, fit: Fit) -> ResizeTarget {
+        ResizeTarget {
+            width,
+            height,
+            fit,
+            gravity: Gravity::Center,
+            aspect_ratio: None,
+            enlarge: false,
+            max_dimension: 4096,
+        }
+    }
+
+    #[test]
+    fn contain_does_not_upscale_unless_enlarge() {
+        let plan = plan_resize(800, 600, &target(Some(1600), Some(1600), Fit::Contain));
+        assert_eq!(plan.output_dimensions(), (800, 600));
+        assert!(plan.source.is_full(800, 600));
+        assert!(!plan.is_upscale());
+
+        let plan = plan_resize(800, 600, &target(Some(400), Some(400), Fit::Inside));
+        assert_eq!(plan.output_dimensions(), (400, 300));
+
+        let enlarge = ResizeTarget {
+            enlarge: true,
+            ..target(Some(1600), Some(1600), Fit::Contain)
+        };
+        let plan = plan_resize(800, 600, &enlarge);
+        assert_eq!(plan.output_dimensions(), (1600, 1200));
+        assert!(plan.is_upscale());
+    }
+
+    #[test]
+    fn enlarge_is_capped_by_max_dimension() {
+        let enlarge = ResizeTarget {
+            enlarge: true,
+            ..target(Some(10000), None, Fit::Contain)
+        };
+        assert_eq!(
+            plan_resize(800, 600, &enlarge).output_dimensions(),
+            (4096, 3072)
+        );
+        // 上限を超える原本でも、上限に合わせて縮小はしない
+        assert_eq!(
+            plan_resize(6000, 3000, &enlarge).output_dimensions(),
+            (6000, 3000)
+        );
+
+        let outside = ResizeTarget {
+            enlarge: true,
+            ..target(Some(10000), Some(100), Fit::Outside)
+        };
+        assert_eq!(
+            plan_resize(800, 600, &outside).output_dimensions(),
+            (4096, 3072)
+        );
+    }
+
+    #[test]
+    fn zero_targets_and_sources_give_at_least_1px() {
+        let plan = plan_resize(800, 600, &target(Some(0), None, Fit::Contain));
+        assert_eq!(plan.output_dimensions(), (1, 1));
+        let plan = plan_resize(800, 600, &target(Some(0), Some(0), Fit::Fill));
+        assert_eq!(plan.output_dimensions(), (1, 1));
+
+        // 幅・高さが 0 の原本でも、出力は 1px 以上になる
+        for fit in [Fit::Contain, Fit::Cover, Fit::Fill, Fit::Outside, Fit::Pad] {
+            let (width, height) =
+                plan_resize(0, 0, &target(Some(100), Some(100), fit)).output_dimensions();
+            assert!((1..=100).contains(&width), "{fit:?}");
+            assert!((1..=100).contains(&height), "{fit:?}");
+        }
+    }
+
+    #[test]
+    fn overflowing_targets_stay_within_source() {
+        let plan = plan_resize(800, 600, &target(Some(u32::MAX), Some(u32::MAX), Fit::Fill));
+        assert_eq!(plan.output_dimensions(), (800, 600));
+
+        let plan = plan_resize(800, 600, &target(Some(u32::MAX), Some(1), Fit::Cover));
+        assert_eq!(plan.output_dimensions(), (800, 1));
+        assert!(plan.source.width <= 800.0 && plan.source.height <= 600.0);
+
+        // 極端なアスペクト比で算出した高さは u32 の範囲に飽和する
+        let extreme = ResizeTarget {
+            aspect_ratio: Some(1e-12),
+            ..target(Some(100), None, Fit::Contain)
+        };
+        assert_eq!(
+            plan_resize(800, 600, &extreme).output_dimensions(),
+            (100, 75)
+        );
+    }
+
+    #[test]
+    fn cover_places_window_by_gravity() {
+        let cover = |gravity| ResizeTarget {
+            gravity,
+            ..target(Some(200), Some(200), Fit::Cover)
+        };
+
+        let plan = plan_resize(1000, 500, &cover(Gravity::Center));
+        assert_eq!(plan.output_dimensions(), (200, 200));
+        assert_eq!(
+            plan.source,
+            Region {
+                left: 250.0,
+                top: 0.0,
+                width: 500.0,
+                height: 500.0
+            }
+        );
+
+        let plan = plan_resize(1000, 500, &cover(Gravity::East));
+        assert_eq!(plan.source.left, 500.0);
+
+        // 端の焦点は、切り取り領域が画像からはみ出さないよう端に寄せる
+        let plan = plan_resize(1000, 500, &cover(Gravity::Focal { x: 1.0, y: 0.0 }));
+        assert_eq!((plan.source.left, plan.source.top), (500.0, 0.0));
+        let plan = plan_resize(1000, 500, &cover(Gravity::Focal { x: 0.0, y: 1.0 }));
+        assert_eq!((plan.source.left, plan.source.top), (0.0, 0.0));
+        let plan = plan_resize(1000, 500, &cover(Gravity::Focal { x: 0.3, y: 0.5 }));
+        assert_eq!((plan.source.left, plan.source.top), (50.0, 0.0));
+    }
+
+    #[test]
+    fn cover_without_enlargement_keeps_window_size() {
+        let plan = plan_resize(100, 50, &target(Some(400), Some(400), Fit::Cover));
+        assert_eq!(plan.output_dimensions(), (50, 50));
+        assert_eq!((plan.source.width, plan.source.height), (50.0, 50.0));
+    }
+
+    #[test]
+    fn pad_places_image_on_canvas() {
+        let pad = ResizeTarget {
+            gravity: Gravity::SouthEast,
+            ..target(Some(400), Some(400), Fit::Pad)
+        };
+        let plan = plan_resize(800, 400, &pad);
+        assert_eq!((plan.width, plan.height), (400, 200));
+        assert_eq!(
+            plan.canvas,
+            Some(Canvas {
+                width: 400,
+                height: 400,
+                x: 0,
+                y: 200
+            })
+        );
+    }
+
+    #[test]
+    fn aspect_ratio_fills_in_missing_dimension() {
+        let with_ar = |width, height| ResizeTarget {
+            aspect_ratio: Some(16.0 / 9.0),
+            ..target(width, height, Fit::Cover)
+        };
+        assert_eq!(
+            plan_resize(4000, 3000, &with_ar(Some(1600), None)).output_dimensions(),
+            (1600, 900)
+        );
+        assert_eq!(
+            plan_resize(4000, 3000, &with_ar(None, Some(900))).output_dimensions(),
+            (1600, 900)
+        );
+        // どちらもなければ原本から切り出せる最大サイズ
+        assert_eq!(
+            plan_resize(4000, 3000, &with_ar(None, None)).output_dimensions(),
+            (4000, 2250)
+        );
+    }
+
+    #[test]
+    fn parses_aspect_ratio() {
+        assert_eq!(parse_aspect_ratio("16:9"), Some(16.0 / 9.0));
+        assert_eq!(parse_aspect_ratio(" 4 : 3 "), Some(4.0 / 3.0));
+        assert_eq!(parse_aspect_ratio("1.5:1"), Some(1.5));
+        for invalid in [
+            "16/9", "16:", ":9", "0:1", "1:0", "-4:3", "inf:1", "NaN:1", "a:b",
+        ] {
+            assert_eq!(parse_aspect_ratio(invalid), None, "{invalid}");
+        }
+    }
+
+    #[test]
+    fn parses_crop_rect() {
+        assert_eq!(
+            CropRect::parse("10, 20,50%,25.5%"),
+            Some(CropRect {
+                x: CropLength::Pixels(10),
+                y: CropLength::Pixels(20),
+                width: CropLength::Percent(50.0),
+                height: CropLength::Percent(25.5),
+            })
+        );
+        for invalid in [
+            "1,2,3",
+            "1,2,3,4,5",
+            "a,0,1,1",
+            "-1,0,1,1",
+            "0,0,101%,1",
+            "0,0,-1%,1",
+        ] {
+            assert_eq!(CropRect::parse(invalid), None, "{invalid}");
+        }
+    }
+
+    #[test]
+    fn resolves_crop_rect_within_image() {
+        let resolve = |s| CropRect::parse(s).unwrap().resolve(1000, 500);
+        assert_eq!(resolve("100,50,200,100"), Some((100, 50, 200, 100)));
+        assert_eq!(resolve("0,0,100%,100%"), Some((0, 0, 1000, 500)));
+        // 百分率の丸めで 1px はみ出す分は画像端に揃える
+        assert_eq!(resolve("33.35%,0,66.7%,100%"), Some((334, 0, 666, 500)));
+
+        for outside in [
+            "900,0,200,100",
+            "1000,0,1,1",
+            "0,500,1,1",
+            "50%,0,60%,100%",
+            "0,0,0,100",
+            "4294967295,0,1,1",
+        ] {
+            assert_eq!(resolve(outside), None, "{outside}");
+        }
+    }
+}
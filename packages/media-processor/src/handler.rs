@@ -1,13 +1,22 @@
 use axum::extract::{Path, Query, State};
-use axum::http::{StatusCode, header};
+use axum::http::{HeaderValue, StatusCode, header};
 use axum::response::{IntoResponse, Response};
+use bytes::Bytes;
 use serde::Deserialize;
 
 use crate::AppState;
-use crate::storage::StorageError;
-use crate::transform::{OutputFormat, TransformError, TransformParams};
+use crate::color::ColorSpace;
+use crate::filter::ResizeFilter;
+use crate::geometry::{CropRect, Fit, Gravity, parse_aspect_ratio};
+use crate::memory::MemoryError;
+use crate::pool::PoolError;
+use crate::rotate::Flip;
+use crate::storage::{ObjectResponse, StorageError};
+use crate::transform::{OutputFormat, TransformError, TransformParams, parse_hex_color};
 
 const CACHE_CONTROL_IMMUTABLE: &str = "public, max-age=31536000, immutable";
+/// 変換の待ち行列が満杯のときにクライアントへ返す再試行までの秒数
+const RETRY_AFTER_SECONDS: u64 = 1;
 
 #[derive(Debug, Deserialize)]
 pub struct TransformQuery {
@@ -19,52 +28,98 @@ pub struct TransformQuery {
     pub format: Option<String>,
     #[serde(rename = "q")]
     pub quality: Option<u8>,
+    pub page: Option<u32>,
+    pub lossless: Option<bool>,
+    pub fit: Option<String>,
+    pub gravity: Option<String>,
+    pub fx: Option<f64>,
+    pub fy: Option<f64>,
+    #[serde(rename = "ar")]
+    pub aspect_ratio: Option<String>,
+    pub crop: Option<String>,
+    pub rot: Option<f64>,
+    pub flip: Option<String>,
+    pub bg: Option<String>,
+    pub cs: Option<String>,
+    pub linear: Option<bool>,
+    pub filter: Option<String>,
+    pub sharpen: Option<f32>,
+    pub enlarge: Option<bool>,
+    /// AVIF のエンコード速度（1〜10）
+    pub speed: Option<u8>,
 }
 
 pub async fn health() -> impl IntoResponse {
     (StatusCode::OK, "ok")
 }
 
+/// キャッシュのヒット数・ミス数・使用量を返す（ディスクキャッシュが無効なら null）。
+pub async fn stats(State(state): State<AppState>) -> impl IntoResponse {
+    axum::Json(serde_json::json!({
+        "original_cache": state.original_cache.stats(),
+        "output_cache": state.output_cache.stats(),
+        "disk_cache": state.disk_cache.as_ref().map(|disk_cache| disk_cache.stats()),
+    }))
+}
+
 pub async fn transform(
     State(state): State<AppState>,
     Path(key): Path<String>,
     Query(query): Query<TransformQuery>,
 ) -> Result<Response, AppError> {
     validate_key(&key)?;
+    let params = parse_params(&query, &state)?;
 
-    let format = query
-        .format
-        .as_deref()
-        .map(|f| {
-            OutputFormat::from_str_param(f).ok_or_else(|| {
-                AppError::BadRequest(format!(
-                    "unsupported format '{f}'. supported: jpg, png, webp, avif"
-                ))
-            })
-        })
-        .transpose()?;
-
-    let params = TransformParams {
-        width: query.width,
-        height: query.height,
-        format,
-        quality: query.quality,
-    };
-
-    tracing::info!(key = %key, "fetching object from Storage Proxy");
-    let input_bytes = state.storage_client.get_object(&key).await?;
-
-    // メタデータ削除のため、パラメータがなくても必ずデコード→エンコードを実行
     tracing::info!(
         key = %key,
         w = ?params.width,
         h = ?params.height,
         f = ?params.format,
         q = ?params.quality,
+        page = ?params.page,
+        lossless = params.lossless,
+        fit = ?params.fit,
+        gravity = ?params.gravity,
+        ar = ?params.aspect_ratio,
+        crop = ?query.crop,
+        rot = params.rotation,
+        flip = ?params.flip,
+        bg = ?query.bg,
+        cs = ?params.color_space,
+        linear = params.linear_light,
+        filter = ?params.filter,
+        sharpen = params.sharpen,
+        enlarge = params.enlarge,
+        speed = params.avif_speed,
         "transforming image"
     );
 
-    let (output_bytes, content_type) = crate::transform::transform(&input_bytes, &params)?;
+    // 同じ変換の同時リクエストは 1 回の取得・変換にまとめる。キーはパース済みの値を正規化した
+    // ものなので、クエリの表記揺れ（大文字小文字、rot=0 と rot=360 等）があってもまとめられる
+    let flight_key = params.cache_key(&key);
+    let (output_bytes, content_type) = match state.output_cache.get(&flight_key) {
+        Some(output) => output,
+        None => {
+            state
+                .transform_flights
+                .clone()
+                .run(flight_key.clone(), || async move {
+                    let output = match cached_output(&state, &flight_key).await {
+                        Some(output) => output,
+                        None => {
+                            let output = fetch_and_transform(&state, key, params).await?;
+                            store_output(&state, &flight_key, &output);
+                            output
+                        }
+                    };
+                    state
+                        .output_cache
+                        .insert(flight_key, output.clone(), output.0.len());
+                    Ok(output)
+                })
+                .await?
+        }
+    };
 
     Ok((
         StatusCode::OK,
@@ -77,6 +132,239 @@ pub async fn transform(
         .into_response())
 }
 
+/// ディスクキャッシュ、ストレージに保存済みの変換結果の順に探す。
+///
+/// ストレージで見つかった結果はディスクキャッシュにも保存する。
+async fn cached_output(state: &AppState, flight_key: &str) -> Option<(Bytes, &'static str)> {
+    if let Some(disk_cache) = &state.disk_cache
+        && let Some(output) = disk_cache.get(flight_key).await
+    {
+        return Some(output);
+    }
+
+    let output = state.variant_store.as_ref()?.get(flight_key).await?;
+    if let Some(disk_cache) = state.disk_cache.clone() {
+        let (flight_key, output) = (flight_key.to_string(), output.clone());
+        tokio::spawn(async move {
+            disk_cache.insert(&flight_key, &output).await;
+        });
+    }
+    Some(output)
+}
+
+/// 変換結果をディスクキャッシュとストレージに保存する。書き込みを待たずにレスポンスを返す。
+fn store_output(state: &AppState, flight_key: &str, output: &(Bytes, &'static str)) {
+    if let Some(disk_cache) = state.disk_cache.clone() {
+        let (flight_key, output) = (flight_key.to_string(), output.clone());
+        tokio::spawn(async move {
+            disk_cache.insert(&flight_key, &output).await;
+        });
+    }
+    if let Some(variant_store) = state.variant_store.clone() {
+        let (flight_key, output) = (flight_key.to_string(), output.clone());
+        tokio::spawn(async move {
+            variant_store.put(&flight_key, &output).await;
+        });
+    }
+}
+
+/// 原本を取得して変換する。
+async fn fetch_and_transform(
+    state: &AppState,
+    key: String,
+    params: TransformParams,
+) -> Result<(Bytes, &'static str), AppError> {
+    // サイズ違いの同時リクエストも、原本のダウンロードは 1 回にまとめる
+    let input_bytes = state
+        .fetch_flights
+        .run(key.clone(), || fetch_original(state, &key))
+        .await?;
+
+    // メタデータ削除のため、パラメータがなくても必ずデコード→エンコードを実行
+    // デコード後のサイズをヘッダから見積もり、メモリ予算を確保してから変換する
+    let estimated_bytes = crate::transform::estimate_memory(&input_bytes, &params).unwrap_or(0);
+    let reservation = state.memory_budget.reserve(estimated_bytes).await?;
+
+    // CPU を占有する変換は非同期ランタイムの外で実行する。予算は変換の完了時に返却する
+    let decode_limits = state.decode_limits;
+    let output = state
+        .transform_pool
+        .run(move || {
+            let _reservation = reservation;
+            crate::transform::transform(&input_bytes, &params, &decode_limits)
+        })
+        .await??;
+    Ok(output)
+}
+
+/// 原本を取得する。キャッシュにある場合は ETag で条件付きリクエストを送り、
+/// 更新されていなければ本文をダウンロードせずにキャッシュを使う。
+async fn fetch_original(state: &AppState, key: &str) -> Result<Bytes, StorageError> {
+    let cached = state.original_cache.get(key);
+    tracing::info!(key = %key, cached = cached.is_some(), "fetching object from storage");
+
+    let if_none_match = cached.as_ref().map(|(etag, _)| etag.as_str());
+    match state.storage.get(key, if_none_match).await? {
+        ObjectResponse::NotModified => match cached {
+            Some((_, data)) => Ok(data),
+            // If-None-Match を送っていなければ 304 は返らない
+            None => Err(StorageError::Internal(
+                "unexpected 304 without If-None-Match".to_string(),
+            )),
+        },
+        ObjectResponse::Modified { data, etag, .. } => {
+            if let Some(etag) = etag {
+                let size = data.len();
+                state
+                    .original_cache
+                    .insert(key.to_string(), (etag, data.clone()), size);
+            }
+            Ok(data)
+        }
+    }
+}
+
+/// クエリ文字列を TransformParams に変換する。値の範囲チェックは transform 側で行う。
+///
+/// `linear` / `speed` が未指定の場合はサーバーの既定値（RESIZE_LINEAR_LIGHT / AVIF_SPEED）を使う。
+fn parse_params(query: &TransformQuery, state: &AppState) -> Result<TransformParams, AppError> {
+    let format = query
+        .format
+        .as_deref()
+        .map(|f| {
+            OutputFormat::from_str_param(f).ok_or_else(|| {
+                AppError::BadRequest(format!(
+                    "unsupported format '{f}'. supported: jpg, png, webp, avif"
+                ))
+            })
+        })
+        .transpose()?;
+
+    let fit = query
+        .fit
+        .as_deref()
+        .map(|f| {
+            Fit::from_str_param(f).ok_or_else(|| {
+                AppError::BadRequest(format!(
+                    "unsupported fit '{f}'. supported: contain, inside, cover, fill, outside, pad"
+                ))
+            })
+        })
+        .transpose()?;
+
+    let gravity = match (query.gravity.as_deref(), query.fx, query.fy) {
+        (None, None, None) => Gravity::default(),
+        (Some(g), None, None) => Gravity::from_str_param(g).ok_or_else(|| {
+            AppError::BadRequest(format!(
+                "unsupported gravity '{g}'. supported: center, north, south, east, west, \
+                 northeast, northwest, southeast, southwest, smart"
+            ))
+        })?,
+        (None, Some(x), Some(y)) => Gravity::Focal { x, y },
+        (Some(_), _, _) => {
+            return Err(AppError::BadRequest(
+                "gravity cannot be combined with fx/fy".to_string(),
+            ));
+        }
+        _ => {
+            return Err(AppError::BadRequest(
+                "fx and fy must be specified together".to_string(),
+            ));
+        }
+    };
+
+    let aspect_ratio = query
+        .aspect_ratio
+        .as_deref()
+        .map(|ar| {
+            parse_aspect_ratio(ar).ok_or_else(|| {
+                AppError::BadRequest(format!(
+                    "invalid ar '{ar}'. expected <width>:<height> (e.g. 16:9)"
+                ))
+            })
+        })
+        .transpose()?;
+
+    let crop = query
+        .crop
+        .as_deref()
+        .map(|c| {
+            CropRect::parse(c).ok_or_else(|| {
+                AppError::BadRequest(format!(
+                    "invalid crop '{c}'. expected x,y,w,h in pixels or percentages (e.g. 10%,10%,50%,50%)"
+                ))
+            })
+        })
+        .transpose()?;
+
+    let flip = query
+        .flip
+        .as_deref()
+        .map(|f| {
+            Flip::from_str_param(f).ok_or_else(|| {
+                AppError::BadRequest(format!("unsupported flip '{f}'. supported: h, v, hv"))
+            })
+        })
+        .transpose()?;
+
+    let background = query
+        .bg
+        .as_deref()
+        .map(|bg| {
+            parse_hex_color(bg).ok_or_else(|| {
+                AppError::BadRequest(format!(
+                    "invalid bg '{bg}'. expected hex color RRGGBB or RRGGBBAA"
+                ))
+            })
+        })
+        .transpose()?;
+
+    let color_space = query
+        .cs
+        .as_deref()
+        .map(|cs| {
+            ColorSpace::from_str_param(cs).ok_or_else(|| {
+                AppError::BadRequest(format!("unsupported cs '{cs}'. supported: srgb, p3, keep"))
+            })
+        })
+        .transpose()?
+        .unwrap_or_default();
+
+    let filter = query
+        .filter
+        .as_deref()
+        .map(|f| {
+            ResizeFilter::from_str_param(f).ok_or_else(|| {
+                AppError::BadRequest(format!(
+                    "unsupported filter '{f}'. supported: nearest, bilinear, catmull-rom, mitchell, lanczos3, box"
+                ))
+            })
+        })
+        .transpose()?;
+
+    Ok(TransformParams {
+        width: query.width,
+        height: query.height,
+        format,
+        quality: query.quality,
+        page: query.page,
+        lossless: query.lossless.unwrap_or(false),
+        fit,
+        gravity,
+        aspect_ratio,
+        crop,
+        rotation: query.rot.unwrap_or(0.0),
+        flip,
+        background,
+        color_space,
+        linear_light: query.linear.unwrap_or(state.linear_light_default),
+        filter,
+        sharpen: query.sharpen.unwrap_or(0.0),
+        enlarge: query.enlarge.unwrap_or(false),
+        avif_speed: query.speed.unwrap_or(state.avif_speed_default),
+    })
+}
+
 /// パストラバーサル攻撃を防ぐためにオブジェクトキーを検証する。
 fn validate_key(key: &str) -> Result<(), AppError> {
     if key.is_empty() {
@@ -118,13 +406,15 @@ fn validate_key(key: &str) -> Result<(), AppError> {
     Ok(())
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 #[allow(dead_code)]
 pub enum AppError {
     BadRequest(String),
     NotFound(String),
     TransformFailed(String),
+    PayloadTooLarge(String),
     StorageUnavailable(String),
+    Overloaded(String),
     Internal(String),
 }
 
@@ -147,6 +437,27 @@ impl From<StorageError> for AppError {
     }
 }
 
+impl From<PoolError> for AppError {
+    fn from(err: PoolError) -> Self {
+        match err {
+            PoolError::Overloaded => {
+                tracing::warn!("transform queue is full, rejecting request");
+                AppError::Overloaded("server is busy, please retry later".to_string())
+            }
+            PoolError::TaskFailed(msg) => {
+                AppError::Internal(format!("transform task failed: {msg}"))
+            }
+        }
+    }
+}
+
+impl From<MemoryError> for AppError {
+    fn from(err: MemoryError) -> Self {
+        tracing::warn!(error = %err, "rejecting request");
+        AppError::Overloaded("server is busy, please retry later".to_string())
+    }
+}
+
 impl From<TransformError> for AppError {
     fn from(err: TransformError) -> Self {
         match err {
@@ -160,6 +471,10 @@ impl From<TransformError> for AppError {
                     "image resolution {width}x{height} exceeds maximum 4096x4096"
                 ))
             }
+            TransformError::SourceTooLarge(msg) => {
+                tracing::warn!(error = %msg, "source image exceeds decode limits");
+                AppError::PayloadTooLarge(format!("source image is too large: {msg}"))
+            }
             TransformError::ProcessingFailed(msg) => {
                 tracing::error!(error = %msg, "image processing failed");
                 AppError::TransformFailed(msg)
@@ -170,14 +485,17 @@ impl From<TransformError> for AppError {
 
 impl IntoResponse for AppError {
     fn into_response(self) -> Response {
+        let retry_after = matches!(self, AppError::Overloaded(_));
         let (status, message) = match self {
             AppError::BadRequest(msg) => (StatusCode::BAD_REQUEST, msg),
             AppError::NotFound(msg) => (StatusCode::NOT_FOUND, msg),
             AppError::TransformFailed(msg) => (StatusCode::UNPROCESSABLE_ENTITY, msg),
+            AppError::PayloadTooLarge(msg) => (StatusCode::PAYLOAD_TOO_LARGE, msg),
             AppError::StorageUnavailable(msg) => {
                 tracing::error!(error = %msg, "storage unavailable");
                 (StatusCode::BAD_GATEWAY, "storage unavailable".to_string())
             }
+            AppError::Overloaded(msg) => (StatusCode::SERVICE_UNAVAILABLE, msg),
             AppError::Internal(msg) => {
                 tracing::error!(error = %msg, "internal server error");
                 (
@@ -188,6 +506,12 @@ impl IntoResponse for AppError {
         };
 
         let body = serde_json::json!({ "error": message });
-        (status, axum::Json(body)).into_response()
+        let mut response = (status, axum::Json(body)).into_response();
+        if retry_after {
+            response
+                .headers_mut()
+                .insert(header::RETRY_AFTER, HeaderValue::from(RETRY_AFTER_SECONDS));
+        }
+        response
     }
 }
@@ -0,0 +1,102 @@
+//! 原本のデコード時の制限（解凍爆弾対策）。
+//!
+//! 数 KB の PNG でも巨大な解像度を宣言できるため、デコード前にヘッダの解像度を検証し、
+//! デコーダにもメモリ確保の上限を渡して、宣言と実データが食い違う場合も確保前に止める。
+
+use crate::config::env_usize;
+
+#[derive(Debug, Clone, Copy)]
+pub struct DecodeLimits {
+    pub max_width: u32,
+    pub max_height: u32,
+    pub max_pixels: u64,
+    /// デコーダが 1 回に確保できるバイト数
+    pub max_alloc_bytes: u64,
+}
+
+impl Default for DecodeLimits {
+    fn default() -> Self {
+        Self {
+            max_width: 16_384,
+            max_height: 16_384,
+            max_pixels: 100_000_000,
+            max_alloc_bytes: 512 * 1024 * 1024,
+        }
+    }
+}
+
+impl DecodeLimits {
+    /// 環境変数から DecodeLimits を作成する。
+    ///
+    /// 任意の環境変数:
+    /// - DECODE_MAX_WIDTH: 原本の最大幅（デフォルト: 16384）
+    /// - DECODE_MAX_HEIGHT: 原本の最大高さ（デフォルト: 16384）
+    /// - DECODE_MAX_PIXELS: 原本の最大ピクセル数（デフォルト: 100000000）
+    /// - DECODE_MAX_ALLOC_MB: デコーダが確保できる最大メモリ量（デフォルト: 512）
+    pub fn from_env() -> Self {
+        let default = Self::default();
+        let limits = Self {
+            max_width: env_usize("DECODE_MAX_WIDTH", default.max_width as usize)
+                .min(u32::MAX as usize) as u32,
+            max_height: env_usize("DECODE_MAX_HEIGHT", default.max_height as usize)
+                .min(u32::MAX as usize) as u32,
+            max_pixels: env_usize("DECODE_MAX_PIXELS", default.max_pixels as usize) as u64,
+            max_alloc_bytes: env_usize(
+                "DECODE_MAX_ALLOC_MB",
+                (default.max_alloc_bytes / 1024 / 1024) as usize,
+            ) as u64
+                * 1024
+                * 1024,
+        };
+
+        tracing::info!(
+            max_width = limits.max_width,
+            max_height = limits.max_height,
+            max_pixels = limits.max_pixels,
+            max_alloc_bytes = limits.max_alloc_bytes,
+            "decode limits configured"
+        );
+        limits
+    }
+
+    /// 解像度が制限内かどうか。超えている場合は理由を返す。
+    pub fn check(&self, width: u32, height: u32) -> Result<(), String> {
+        if width > self.max_width || height > self.max_height {
+            return Err(format!(
+                "{width}x{height} exceeds maximum {}x{}",
+                self.max_width, self.max_height
+            ));
+        }
+        let pixels = width as u64 * height as u64;
+        if pixels > self.max_pixels {
+            return Err(format!(
+                "{width}x{height} ({pixels} pixels) exceeds maximum {} pixels",
+                self.max_pixels
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn image_limits(&self) -> image::Limits {
+        let mut limits = image::Limits::default();
+        limits.max_image_width = Some(self.max_width);
+        limits.max_image_height = Some(self.max_height);
+        limits.max_alloc = Some(self.max_alloc_bytes);
+        limits
+    }
+
+    pub fn heic_limits(&self) -> heic::Limits {
+        let mut limits = heic::Limits::default();
+        limits.max_width = Some(self.max_width as u64);
+        limits.max_height = Some(self.max_height as u64);
+        limits.max_pixels = Some(self.max_pixels);
+        limits.max_memory_bytes = Some(self.max_alloc_bytes);
+        limits
+    }
+
+    pub fn tiff_limits(&self) -> tiff::decoder::Limits {
+        let mut limits = tiff::decoder::Limits::default();
+        limits.decoding_buffer_size = usize::try_from(self.max_alloc_bytes).unwrap_or(usize::MAX);
+        limits
+    }
+}
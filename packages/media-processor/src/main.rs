@@ -1,22 +1,71 @@
+mod avif;
+mod cache;
+mod color;
+mod config;
+mod disk_cache;
+mod filter;
+mod fs;
+mod geometry;
 mod handler;
+mod limits;
+mod memory;
+mod pool;
+mod rotate;
+mod singleflight;
+mod smartcrop;
 mod storage;
+mod sync;
 mod transform;
+mod variants;
 
 use std::net::SocketAddr;
+use std::sync::Arc;
 
 use axum::Router;
 use axum::routing::get;
+use bytes::Bytes;
 use tokio::signal;
 use tower_http::trace::TraceLayer;
 use tracing_subscriber::layer::SubscriberExt;
 use tracing_subscriber::util::SubscriberInitExt;
 use tracing_subscriber::{EnvFilter, fmt};
 
-use crate::storage::StorageProxyClient;
+use crate::cache::ByteLru;
+use crate::config::{env_bool, env_usize};
+use crate::disk_cache::DiskCache;
+use crate::handler::AppError;
+use crate::limits::DecodeLimits;
+use crate::memory::MemoryBudget;
+use crate::pool::TransformPool;
+use crate::singleflight::Group;
+use crate::storage::{Storage, StorageError};
+use crate::transform::{AVIF_SPEED_RANGE, DEFAULT_AVIF_SPEED};
+use crate::variants::VariantStore;
 
 #[derive(Clone)]
 pub struct AppState {
-    pub storage_client: StorageProxyClient,
+    /// 原本・変換結果のストレージ（STORAGE_BACKEND で選択）
+    pub storage: Arc<dyn Storage>,
+    pub transform_pool: TransformPool,
+    pub memory_budget: MemoryBudget,
+    pub decode_limits: DecodeLimits,
+    /// `linear` 未指定時に線形光でリサイズするか（RESIZE_LINEAR_LIGHT）
+    pub linear_light_default: bool,
+    /// `speed` 未指定時の AVIF のエンコード速度（AVIF_SPEED）
+    pub avif_speed_default: u8,
+    /// 同じ原本の同時取得をまとめる（キーはオブジェクトキー）
+    pub fetch_flights: Group<String, Result<Bytes, StorageError>>,
+    /// 同じ変換の同時実行をまとめる（キーは `TransformParams::cache_key`）
+    pub transform_flights: Group<String, Result<(Bytes, &'static str), AppError>>,
+    /// 取得した原本（キーはオブジェクトキー、値は ETag と本文）
+    pub original_cache: ByteLru<String, (String, Bytes)>,
+    /// 小さな変換結果（キーは transform_flights と同じ）。原本はアップロードごとに新しいキー（UUID）で
+    /// 保存され内容が変わらないため、キーに原本の ETag は含めない
+    pub output_cache: ByteLru<String, (Bytes, &'static str)>,
+    /// 変換結果のディスクキャッシュ（DISK_CACHE_DIR 未設定なら None、キーは transform_flights と同じ）
+    pub disk_cache: Option<DiskCache>,
+    /// ストレージの `variants/` 以下に保存する変換結果（PERSIST_VARIANTS 未設定なら None）
+    pub variant_store: Option<VariantStore>,
 }
 
 #[tokio::main]
@@ -28,15 +77,50 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         .with(fmt::layer().json())
         .init();
 
-    let storage_client = StorageProxyClient::from_env().map_err(|e| {
-        tracing::error!("Failed to initialize Storage Proxy client: {}", e);
+    let storage = storage::from_env().map_err(|e| {
+        tracing::error!("Failed to initialize storage backend: {}", e);
         e
     })?;
-    let state = AppState { storage_client };
+    pool::init_worker_threads();
+    let transform_pool = TransformPool::from_env();
+    let memory_budget = MemoryBudget::from_env();
+    let decode_limits = DecodeLimits::from_env();
+    let linear_light_default = env_bool("RESIZE_LINEAR_LIGHT", false);
+    let avif_speed_default = env_usize("AVIF_SPEED", DEFAULT_AVIF_SPEED as usize).clamp(
+        *AVIF_SPEED_RANGE.start() as usize,
+        *AVIF_SPEED_RANGE.end() as usize,
+    ) as u8;
+    // 任意の環境変数（0 でキャッシュしない）:
+    // - ORIGINAL_CACHE_MB: 原本のキャッシュ容量（デフォルト: 128）
+    // - OUTPUT_CACHE_MB: 変換結果のキャッシュ容量（デフォルト: 32）
+    let original_cache_mb = env_usize("ORIGINAL_CACHE_MB", 128);
+    let output_cache_mb = env_usize("OUTPUT_CACHE_MB", 32);
+    tracing::info!(
+        original_cache_mb,
+        output_cache_mb,
+        "in-memory caches configured"
+    );
+    let disk_cache = DiskCache::from_env();
+    let variant_store = VariantStore::from_env(&storage);
+    let state = AppState {
+        storage,
+        transform_pool,
+        memory_budget,
+        decode_limits,
+        linear_light_default,
+        avif_speed_default,
+        fetch_flights: Group::new(),
+        transform_flights: Group::new(),
+        original_cache: ByteLru::new(original_cache_mb * 1024 * 1024),
+        output_cache: ByteLru::new(output_cache_mb * 1024 * 1024),
+        disk_cache,
+        variant_store,
+    };
 
     let app = Router::new()
         .route("/transform/{*key}", get(handler::transform))
         .route("/health", get(handler::health))
+        .route("/stats", get(handler::stats))
         .layer(TraceLayer::new_for_http())
         .with_state(state);
 
@@ -0,0 +1,85 @@
+//! デコード後のピクセルサイズに基づくメモリ予算の受付制御。
+//!
+//! 同時実行数だけでは 48MP の画像が重なった場合のメモリ使用量を抑えられないため、
+//! デコード前にヘッダから見積もったメモリ量を予算から確保し、変換が終わるまで保持する。
+//! 予算が足りない場合は空くまで待ち、一定時間待っても確保できなければ拒否する。
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::config::env_usize;
+
+/// 予算を管理する単位（KiB）。セマフォの許可数が u32 に収まるようにする。
+const UNIT_BYTES: u64 = 1024;
+
+#[derive(Debug, thiserror::Error)]
+pub enum MemoryError {
+    #[error("memory budget exhausted (requested {requested_bytes} bytes)")]
+    Exhausted { requested_bytes: u64 },
+}
+
+#[derive(Clone)]
+pub struct MemoryBudget {
+    permits: Arc<Semaphore>,
+    capacity_units: u32,
+    wait_timeout: Duration,
+}
+
+/// 確保したメモリ予算。drop で予算に返却される。
+pub struct MemoryReservation {
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+impl MemoryBudget {
+    /// 環境変数から MemoryBudget を作成する。
+    ///
+    /// 任意の環境変数:
+    /// - TRANSFORM_MEMORY_BUDGET_MB: 変換全体で同時に使えるメモリ量（デフォルト: 512）
+    /// - TRANSFORM_MEMORY_WAIT_SECS: 予算が空くまで待つ最大秒数（デフォルト: 10）
+    pub fn from_env() -> Self {
+        let budget_mb = env_usize("TRANSFORM_MEMORY_BUDGET_MB", 512).max(1);
+        let wait_secs = env_usize("TRANSFORM_MEMORY_WAIT_SECS", 10);
+
+        tracing::info!(budget_mb, wait_secs, "transform memory budget configured");
+        Self::new(
+            budget_mb as u64 * 1024 * 1024,
+            Duration::from_secs(wait_secs as u64),
+        )
+    }
+
+    pub fn new(capacity_bytes: u64, wait_timeout: Duration) -> Self {
+        let capacity_units = to_units(capacity_bytes).max(1);
+        Self {
+            permits: Arc::new(Semaphore::new(capacity_units as usize)),
+            capacity_units,
+            wait_timeout,
+        }
+    }
+
+    /// `bytes` 分の予算を確保する。空きがなければ最大 `wait_timeout` まで待つ。
+    ///
+    /// 予算全体を超える見積もりは予算全体に切り詰める（他の変換が終わるのを待って単独で実行する）。
+    pub async fn reserve(&self, bytes: u64) -> Result<MemoryReservation, MemoryError> {
+        let units = to_units(bytes).min(self.capacity_units);
+        if units == 0 {
+            return Ok(MemoryReservation { _permit: None });
+        }
+
+        let acquire = self.permits.clone().acquire_many_owned(units);
+        match tokio::time::timeout(self.wait_timeout, acquire).await {
+            Ok(Ok(permit)) => Ok(MemoryReservation {
+                _permit: Some(permit),
+            }),
+            // セマフォは close しないため、Err になるのはタイムアウトのみ
+            Ok(Err(_)) | Err(_) => Err(MemoryError::Exhausted {
+                requested_bytes: bytes,
+            }),
+        }
+    }
+}
+
+fn to_units(bytes: u64) -> u32 {
+    bytes.div_ceil(UNIT_BYTES).min(u32::MAX as u64) as u32
+}
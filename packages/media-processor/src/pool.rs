@@ -0,0 +1,126 @@
+//! 変換処理（デコード・リサイズ・エンコード）を非同期ランタイムの外で実行するプール。
+//!
+//! CPU を占有する変換を Tokio のワーカースレッドで直接実行すると、同じワーカー上の
+//! 他のリクエスト（/health を含む）が止まるため、blocking スレッドで実行する。
+//! 同時実行数と待ち行列の長さを制限し、待ち行列が満杯なら即座に拒否する。
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use tokio::sync::Semaphore;
+
+use crate::config::env_usize;
+
+#[derive(Debug, thiserror::Error)]
+pub enum PoolError {
+    #[error("transform queue is full")]
+    Overloaded,
+
+    #[error("transform task failed: {0}")]
+    TaskFailed(String),
+}
+
+#[derive(Clone)]
+pub struct TransformPool {
+    permits: Arc<Semaphore>,
+    queued: Arc<AtomicUsize>,
+    max_queued: usize,
+}
+
+impl TransformPool {
+    /// 環境変数から TransformPool を作成する。
+    ///
+    /// 任意の環境変数:
+    /// - TRANSFORM_CONCURRENCY: 同時に実行する変換数（デフォルト: CPU 数）
+    /// - TRANSFORM_QUEUE_SIZE: 実行待ちにできる変換数（デフォルト: 同時実行数 × 4）
+    pub fn from_env() -> Self {
+        let concurrency = env_usize("TRANSFORM_CONCURRENCY", available_cpus()).max(1);
+        let max_queued = env_usize("TRANSFORM_QUEUE_SIZE", concurrency * 4);
+
+        tracing::info!(concurrency, max_queued, "transform pool configured");
+        Self::new(concurrency, max_queued)
+    }
+
+    pub fn new(concurrency: usize, max_queued: usize) -> Self {
+        Self {
+            permits: Arc::new(Semaphore::new(concurrency)),
+            queued: Arc::new(AtomicUsize::new(0)),
+            max_queued,
+        }
+    }
+
+    /// `f` を blocking スレッドで実行する。空きがなければ待ち行列で待ち、待ち行列も満杯なら
+    /// `PoolError::Overloaded` を返す。
+    ///
+    /// 呼び出し側のリクエストが途中でキャンセルされても、実行を開始した変換は最後まで走り、
+    /// 終了時に枠を返却する。
+    pub async fn run<F, T>(&self, f: F) -> Result<T, PoolError>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let permit = match self.permits.clone().try_acquire_owned() {
+            Ok(permit) => permit,
+            Err(_) => {
+                let _slot = QueueSlot::acquire(&self.queued, self.max_queued)
+                    .ok_or(PoolError::Overloaded)?;
+                self.permits
+                    .clone()
+                    .acquire_owned()
+                    .await
+                    .map_err(|e| PoolError::TaskFailed(e.to_string()))?
+            }
+        };
+
+        tokio::task::spawn_blocking(move || {
+            let _permit = permit;
+            f()
+        })
+        .await
+        .map_err(|e| PoolError::TaskFailed(e.to_string()))
+    }
+}
+
+/// 待ち行列の 1 枠。待機が終わる（またはキャンセルされる）と枠を返却する。
+struct QueueSlot<'a>(&'a AtomicUsize);
+
+impl<'a> QueueSlot<'a> {
+    fn acquire(queued: &'a AtomicUsize, max_queued: usize) -> Option<Self> {
+        queued
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |n| {
+                (n < max_queued).then_some(n + 1)
+            })
+            .ok()
+            .map(|_| Self(queued))
+    }
+}
+
+impl Drop for QueueSlot<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// 1 つの変換の内部（AVIF エンコード・リサイズ）で並列に使うスレッドを設定する。
+///
+/// スレッドはすべての変換で共有するため、同時に走る変換が増えても CPU 数以上には増えない。
+///
+/// 任意の環境変数:
+/// - TRANSFORM_THREADS: 共有するスレッド数（デフォルト: CPU 数。1 なら並列化しない）
+pub fn init_worker_threads() {
+    let threads = env_usize("TRANSFORM_THREADS", available_cpus()).max(1);
+    let result = rayon::ThreadPoolBuilder::new()
+        .num_threads(threads)
+        .thread_name(|i| format!("transform-worker-{i}"))
+        .build_global();
+    match result {
+        Ok(()) => tracing::info!(threads, "transform worker threads configured"),
+        Err(e) => tracing::warn!(error = %e, "transform worker threads already configured"),
+    }
+}
+
+fn available_cpus() -> usize {
+    std::thread::available_parallelism()
+        .map(|n| n.get())
+        .unwrap_or(1)
+}
@@ -0,0 +1,265 @@
+//! rot / flip による手動の回転・反転。
+
+use image::{DynamicImage, ImageBuffer, Pixel, Primitive, Rgba};
+
+use crate::limits::DecodeLimits;
+
+/// 反転方向。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Flip {
+    /// 左右反転
+    Horizontal,
+    /// 上下反転
+    Vertical,
+    /// 左右・上下の両方（180° 回転と同じ）
+    Both,
+}
+
+impl Flip {
+    pub fn from_str_param(s: &str) -> Option<Self> {
+        match s.to_lowercase().as_str() {
+            "h" => Some(Self::Horizontal),
+            "v" => Some(Self::Vertical),
+            "hv" | "vh" => Some(Self::Both),
+            _ => None,
+        }
+    }
+}
+
+/// 画像を時計回りに `degrees` 度回転する。
+///
+/// 90° の倍数はピクセルの並べ替えだけで行う。それ以外の角度は回転後の画像全体が収まるよう
+/// キャンバスを広げ、四隅の余白を `background` で埋める（境界はバイリニア補間で馴染ませる）。
+/// 広げたキャンバスが `limits` を超える場合は確保前にエラーを返す。16bit・浮動小数点の画像は
+/// 精度を保ったまま、それ以外は RGBA8 にして回転する（余白の色を表すためアルファを付ける）。
+pub fn rotate(
+    img: DynamicImage,
+    degrees: f64,
+    background: Rgba<u8>,
+    limits: &DecodeLimits,
+) -> Result<DynamicImage, String> {
+    let degrees = degrees.rem_euclid(360.0);
+    let rotated = match degrees {
+        0.0 => img,
+        90.0 => img.rotate90(),
+        180.0 => img.rotate180(),
+        270.0 => img.rotate270(),
+        _ => {
+            let (width, height) = rotated_dimensions(img.width(), img.height(), degrees);
+            limits
+                .check(width, height)
+                .map_err(|e| format!("rotated image {e}"))?;
+            match img {
+                DynamicImage::ImageLuma16(_)
+                | DynamicImage::ImageLumaA16(_)
+                | DynamicImage::ImageRgb16(_)
+                | DynamicImage::ImageRgba16(_) => DynamicImage::ImageRgba16(rotate_arbitrary(
+                    &img.to_rgba16(),
+                    degrees,
+                    background,
+                )),
+                DynamicImage::ImageRgb32F(_) | DynamicImage::ImageRgba32F(_) => {
+                    DynamicImage::ImageRgba32F(rotate_arbitrary(
+                        &img.to_rgba32f(),
+                        degrees,
+                        background,
+                    ))
+                }
+                _ => {
+                    DynamicImage::ImageRgba8(rotate_arbitrary(&img.to_rgba8(), degrees, background))
+                }
+            }
+        }
+    };
+    Ok(rotated)
+}
+
+/// `width` × `height` の画像を `degrees` 度回転したときに全体が収まるキャンバスのサイズ。
+pub fn rotated_dimensions(width: u32, height: u32, degrees: f64) -> (u32, u32) {
+    let (sin, cos) = degrees.to_radians().sin_cos();
+    let (width, height) = (width as f64, height as f64);
+    // 90° の倍数で sin・cos に残る誤差で 1px 広がらないよう、わずかに切り下げてから切り上げる
+    let fit = |length: f64| (length - 1e-9).ceil().max(1.0) as u32;
+    (
+        fit(width * cos.abs() + height * sin.abs()),
+        fit(width * sin.abs() + height * cos.abs()),
+    )
+}
+
+pub fn flip(img: DynamicImage, flip: Flip) -> DynamicImage {
+    match flip {
+        Flip::Horizontal => img.fliph(),
+        Flip::Vertical => img.flipv(),
+        Flip::Both => img.rotate180(),
+    }
+}
+
+/// 補間する画素のチャンネルの型（8bit・16bit・浮動小数点）。
+trait Channel: Primitive + Into<f64> {
+    fn from_f64(value: f64) -> Self;
+}
+
+impl Channel for u8 {
+    fn from_f64(value: f64) -> Self {
+        value.round().clamp(0.0, u8::MAX as f64) as u8
+    }
+}
+
+impl Channel for u16 {
+    fn from_f64(value: f64) -> Self {
+        value.round().clamp(0.0, u16::MAX as f64) as u16
+    }
+}
+
+impl Channel for f32 {
+    fn from_f64(value: f64) -> Self {
+        value as f32
+    }
+}
+
+type RgbaBuffer<T> = ImageBuffer<Rgba<T>, Vec<T>>;
+
+fn rotate_arbitrary<T: Channel>(
+    src: &RgbaBuffer<T>,
+    degrees: f64,
+    background: Rgba<u8>,
+) -> RgbaBuffer<T>
+where
+    Rgba<T>: Pixel<Subpixel = T>,
+{
+    let (sin, cos) = degrees.to_radians().sin_cos();
+    let (dst_w, dst_h) = rotated_dimensions(src.width(), src.height(), degrees);
+
+    let (src_cx, src_cy) = (src.width() as f64 / 2.0, src.height() as f64 / 2.0);
+    let (dst_cx, dst_cy) = (dst_w as f64 / 2.0, dst_h as f64 / 2.0);
+    let scale = T::DEFAULT_MAX_VALUE.into() / u8::MAX as f64;
+    let background = Rgba(background.0.map(|c| T::from_f64(c as f64 * scale)));
+
+    ImageBuffer::from_fn(dst_w, dst_h, |x, y| {
+        // 出力ピクセル中心を逆回転してソース座標を求める
+        let dx = x as f64 + 0.5 - dst_cx;
+        let dy = y as f64 + 0.5 - dst_cy;
+        let sx = dx * cos + dy * sin + src_cx - 0.5;
+        let sy = -dx * sin + dy * cos + src_cy - 0.5;
+        sample_bilinear(src, sx, sy, background)
+    })
+}
+
+/// 範囲外の近傍は `background` として扱うバイリニア補間。
+///
+/// 透明な背景と混ざる境界が黒ずまないよう、アルファで重み付けして補間する。
+fn sample_bilinear<T: Channel>(src: &RgbaBuffer<T>, x: f64, y: f64, background: Rgba<T>) -> Rgba<T>
+where
+    Rgba<T>: Pixel<Subpixel = T>,
+{
+    let (x0, y0) = (x.floor(), y.floor());
+    let (fx, fy) = (x - x0, y - y0);
+    let (x0, y0) = (x0 as i64, y0 as i64);
+
+    let pixel = |px: i64, py: i64| -> Rgba<T> {
+        if px >= 0 && py >= 0 && px < src.width() as i64 && py < src.height() as i64 {
+            *src.get_pixel(px as u32, py as u32)
+        } else {
+            background
+        }
+    };
+
+    let neighbors = [
+        (pixel(x0, y0), (1.0 - fx) * (1.0 - fy)),
+        (pixel(x0 + 1, y0), fx * (1.0 - fy)),
+        (pixel(x0, y0 + 1), (1.0 - fx) * fy),
+        (pixel(x0 + 1, y0 + 1), fx * fy),
+    ];
+
+    let mut color = [0.0f64; 3];
+    let mut alpha = 0.0f64;
+    for (p, weight) in neighbors {
+        let a = p[3].into() * weight;
+        for c in 0..3 {
+            color[c] += p[c].into() * a;
+        }
+        alpha += a;
+    }
+
+    if alpha <= 0.0 {
+        return Rgba([T::from_f64(0.0); 4]);
+    }
+    Rgba([
+        T::from_f64(color[0] / alpha),
+        T::from_f64(color[1] / alpha),
+        T::from_f64(color[2] / alpha),
+        T::from_f64(alpha),
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{Rgb, RgbImage, RgbaImage};
+
+    const BLUE: Rgba<u8> = Rgba([0, 0, 255, 255]);
+    const RED: Rgba<u8> = Rgba([255, 0, 0, 255]);
+
+    fn gradient(width: u32, height: u32) -> RgbaImage {
+        RgbaImage::from_fn(width, height, |x, y| {
+            Rgba([(x * 40) as u8, (y * 40) as u8, 128, 255])
+        })
+    }
+
+    #[test]
+    fn arbitrary_rotation_by_90_degrees_matches_pixel_reordering() {
+        let src = gradient(5, 3);
+        let rotated = rotate_arbitrary(&src, 90.0, RED);
+        assert_eq!(rotated, image::imageops::rotate90(&src));
+        let rotated = rotate_arbitrary(&src, 270.0, RED);
+        assert_eq!(rotated, image::imageops::rotate270(&src));
+    }
+
+    #[test]
+    fn rotation_by_45_degrees_fits_the_bounding_box_and_fills_corners() {
+        let src = RgbaImage::from_pixel(100, 50, BLUE);
+        let img = rotate(
+            DynamicImage::ImageRgba8(src),
+            45.0,
+            RED,
+            &DecodeLimits::default(),
+        )
+        .unwrap();
+
+        // (100 + 50) × cos 45° = 106.07 を切り上げ
+        assert_eq!((img.width(), img.height()), (107, 107));
+        let img = img.to_rgba8();
+        for (x, y) in [(0, 0), (106, 0), (0, 106), (106, 106)] {
+            assert_eq!(*img.get_pixel(x, y), RED, "corner ({x}, {y})");
+        }
+        assert_eq!(*img.get_pixel(53, 53), BLUE);
+    }
+
+    #[test]
+    fn arbitrary_rotation_keeps_16_bit_depth() {
+        let src = DynamicImage::ImageRgb16(ImageBuffer::from_pixel(10, 10, Rgb([1000u16; 3])));
+        let img = rotate(src, 30.0, RED, &DecodeLimits::default()).unwrap();
+
+        let DynamicImage::ImageRgba16(img) = img else {
+            panic!("expected a 16-bit image, got {:?}", img.color());
+        };
+        let (w, h) = img.dimensions();
+        assert_eq!(
+            *img.get_pixel(w / 2, h / 2),
+            Rgba([1000, 1000, 1000, u16::MAX])
+        );
+        assert_eq!(*img.get_pixel(0, 0), Rgba([u16::MAX, 0, 0, u16::MAX]));
+    }
+
+    #[test]
+    fn rejects_rotation_beyond_limits() {
+        let limits = DecodeLimits {
+            max_pixels: 10_000,
+            ..DecodeLimits::default()
+        };
+        let src = DynamicImage::ImageRgb8(RgbImage::new(100, 100));
+        // 90° の倍数はキャンバスが広がらないため制限しない
+        assert!(rotate(src.clone(), 90.0, RED, &limits).is_ok());
+        assert!(rotate(src, 45.0, RED, &limits).is_err());
+    }
+}
@@ -0,0 +1,178 @@
+//! 同じキーの処理が同時に走っている間、後続の呼び出しを先行の処理の結果で済ませる（singleflight）。
+//!
+//! ギャラリーの表示時は、複数の家族が同じ画像・同じサイズを同時に要求してエッジのキャッシュを
+//! 外すことがある。原本の取得や変換を 1 回にまとめ、結果を全員で共有する。
+//! 結果はキャッシュせず、処理が終われば次の呼び出しは改めて実行する。
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::hash::Hash;
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::OnceCell;
+
+pub struct Group<K, V> {
+    calls: Arc<Mutex<HashMap<K, Arc<OnceCell<V>>>>>,
+}
+
+impl<K, V> Clone for Group<K, V> {
+    fn clone(&self) -> Self {
+        Self {
+            calls: self.calls.clone(),
+        }
+    }
+}
+
+impl<K, V> Default for Group<K, V> {
+    fn default() -> Self {
+        Self {
+            calls: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+}
+
+impl<K: Eq + Hash + Clone, V: Clone> Group<K, V> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// `key` の処理が実行中ならその結果を待ち、なければ `f` を実行する。
+    ///
+    /// 実行中の呼び出し元がキャンセルされた（クライアントが切断した）場合は、
+    /// 待っている呼び出しのいずれかが代わりに `f` を実行する。
+    pub async fn run<F, Fut>(&self, key: K, f: F) -> V
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = V>,
+    {
+        let cell = self
+            .lock()
+            .entry(key.clone())
+            .or_insert_with(|| Arc::new(OnceCell::new()))
+            .clone();
+        let guard = CallGuard {
+            group: self,
+            key,
+            cell,
+        };
+
+        guard.cell.get_or_init(f).await.clone()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<K, Arc<OnceCell<V>>>> {
+        crate::sync::lock(&self.calls)
+    }
+}
+
+/// 呼び出しの終了時（完了・キャンセルとも）に、不要になったエントリを取り除く。
+struct CallGuard<'a, K: Eq + Hash + Clone, V: Clone> {
+    group: &'a Group<K, V>,
+    key: K,
+    cell: Arc<OnceCell<V>>,
+}
+
+impl<K: Eq + Hash + Clone, V: Clone> Drop for CallGuard<'_, K, V> {
+    fn drop(&mut self) {
+        let mut calls = self.group.lock();
+        let Some(current) = calls.get(&self.key) else {
+            return;
+        };
+        // 完了した場合は後続の呼び出しが新しく実行するよう取り除く。キャンセルされた場合は、
+        // 待っている呼び出しが残っていなければ（マップと自分の参照のみ）取り除く
+        if Arc::ptr_eq(current, &self.cell)
+            && (self.cell.initialized() || Arc::strong_count(&self.cell) <= 2)
+        {
+            calls.remove(&self.key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    use super::*;
+
+    fn is_running(group: &Group<&'static str, u32>, key: &str) -> bool {
+        group.lock().contains_key(key)
+    }
+
+    #[tokio::test]
+    async fn concurrent_calls_share_one_execution() {
+        let group = Group::new();
+        let calls = AtomicUsize::new(0);
+        let f = || async {
+            // 他の呼び出しが待ち始めるまで完了しない
+            tokio::task::yield_now().await;
+            calls.fetch_add(1, Ordering::SeqCst);
+            42
+        };
+
+        let results = tokio::join!(
+            group.run("a", f),
+            group.run("a", f),
+            group.run("a", f),
+            group.run("b", f),
+        );
+        assert_eq!(results, (42, 42, 42, 42));
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn removes_key_after_completion() {
+        let group = Group::new();
+        let calls = AtomicUsize::new(0);
+        let f = || async { calls.fetch_add(1, Ordering::SeqCst) as u32 };
+
+        assert_eq!(group.run("a", f).await, 0);
+        assert!(!is_running(&group, "a"));
+        // 結果はキャッシュしないため、次の呼び出しは改めて実行する
+        assert_eq!(group.run("a", f).await, 1);
+        assert!(!is_running(&group, "a"));
+    }
+
+    #[tokio::test]
+    async fn cancelled_leader_hands_over_to_waiting_call() {
+        let group = Group::new();
+        let leader = tokio::spawn({
+            let group = group.clone();
+            async move { group.run("a", std::future::pending).await }
+        });
+        while !is_running(&group, "a") {
+            tokio::task::yield_now().await;
+        }
+        let follower = tokio::spawn({
+            let group = group.clone();
+            async move { group.run("a", || async { 7 }).await }
+        });
+        // マップ・先行の呼び出し・待っている呼び出しの 3 つが同じエントリを参照するまで待つ
+        while Arc::strong_count(&group.lock()["a"]) < 3 {
+            tokio::task::yield_now().await;
+        }
+
+        leader.abort();
+        let result = tokio::time::timeout(Duration::from_secs(5), follower)
+            .await
+            .expect("follower must not wait for the cancelled leader")
+            .unwrap();
+        assert_eq!(result, 7);
+        assert!(!is_running(&group, "a"));
+    }
+
+    #[tokio::test]
+    async fn cancelled_call_without_waiters_removes_key() {
+        let group = Group::new();
+        let leader = tokio::spawn({
+            let group = group.clone();
+            async move { group.run("a", std::future::pending).await }
+        });
+        while !is_running(&group, "a") {
+            tokio::task::yield_now().await;
+        }
+
+        leader.abort();
+        assert!(leader.await.unwrap_err().is_cancelled());
+        assert!(!is_running(&group, "a"));
+    }
+}
@@ -0,0 +1,199 @@
+//! gravity=smart の切り取り位置推定。
+//!
+//! 縮小コピー上でピクセルごとの「注目度」（エッジ・彩度・肌色）を求め、
+//! 切り取り窓内の合計が最大になる位置を選ぶ。
+
+use image::{DynamicImage, RgbImage};
+
+/// 解析用に縮小するときの長辺サイズ。
+const ANALYSIS_SIZE: u32 = 256;
+/// 窓を動かす刻み（解析画像上のピクセル数）。
+const SEARCH_STEP: usize = 2;
+
+const EDGE_WEIGHT: f64 = 1.0;
+const SATURATION_WEIGHT: f64 = 0.3;
+const SKIN_WEIGHT: f64 = 1.8;
+
+/// 肌色の基準（正規化した RGB ベクトル）。
+const SKIN_COLOR: [f64; 3] = [0.78, 0.57, 0.44];
+const SKIN_THRESHOLD: f64 = 0.8;
+const SKIN_LUMA_RANGE: (f64, f64) = (0.2, 1.0);
+
+/// `img` 上に `window`（幅, 高さ）の矩形を置くとき、注目度が最大になる左上座標を返す。
+pub fn find_best_crop(img: &DynamicImage, window: (f64, f64)) -> (f64, f64) {
+    let (src_w, src_h) = (img.width() as f64, img.height() as f64);
+    let free = ((src_w - window.0).max(0.0), (src_h - window.1).max(0.0));
+    if free.0 < 1.0 && free.1 < 1.0 {
+        return (0.0, 0.0);
+    }
+
+    let small = img.thumbnail(ANALYSIS_SIZE, ANALYSIS_SIZE).to_rgb8();
+    let (w, h) = (small.width() as usize, small.height() as usize);
+    let scale = w as f64 / src_w;
+
+    let table = SummedAreaTable::new(&score_map(&small), w, h);
+
+    let win_w = ((window.0 * scale).round() as usize).clamp(1, w);
+    let win_h = ((window.1 * scale).round() as usize).clamp(1, h);
+
+    let mut best = (f64::MIN, 0, 0);
+    for y in search_offsets(h - win_h) {
+        for x in search_offsets(w - win_w) {
+            let score = table.sum(x, y, win_w, win_h);
+            // 同点なら中央に近い位置を優先する
+            let off_center = (x as f64 - (w - win_w) as f64 / 2.0).abs()
+                + (y as f64 - (h - win_h) as f64 / 2.0).abs();
+            let score = score - off_center * 1e-6;
+            if score > best.0 {
+                best = (score, x, y);
+            }
+        }
+    }
+
+    // 解析画像の端に寄せた窓は、丸め誤差があっても原本の端に合わせる
+    let to_source = |offset: usize, max_offset: usize, free: f64| {
+        if offset == max_offset {
+            free
+        } else {
+            (offset as f64 / scale).clamp(0.0, free)
+        }
+    };
+    (
+        to_source(best.1, w - win_w, free.0),
+        to_source(best.2, h - win_h, free.1),
+    )
+}
+
+/// 窓の位置の候補。`SEARCH_STEP` 刻みで飛ばしても、端（`max_offset`）は必ず含める。
+fn search_offsets(max_offset: usize) -> impl Iterator<Item = usize> {
+    (0..=max_offset)
+        .step_by(SEARCH_STEP)
+        .chain((!max_offset.is_multiple_of(SEARCH_STEP)).then_some(max_offset))
+}
+
+/// ピクセルごとの注目度を計算する。
+fn score_map(img: &RgbImage) -> Vec<f64> {
+    let (w, h) = (img.width() as usize, img.height() as usize);
+    let luma: Vec<f64> = img
+        .pixels()
+        .map(|p| (0.2126 * p[0] as f64 + 0.7152 * p[1] as f64 + 0.0722 * p[2] as f64) / 255.0)
+        .collect();
+
+    let mut scores = vec![0.0; w * h];
+    for (i, pixel) in img.pixels().enumerate() {
+        let (x, y) = (i % w, i / w);
+        let rgb = [
+            pixel[0] as f64 / 255.0,
+            pixel[1] as f64 / 255.0,
+            pixel[2] as f64 / 255.0,
+        ];
+
+        scores[i] = edge(&luma, w, h, x, y) * EDGE_WEIGHT
+            + saturation(rgb, luma[i]) * SATURATION_WEIGHT
+            + skin(rgb, luma[i]) * SKIN_WEIGHT;
+    }
+    scores
+}
+
+/// 4 近傍ラプラシアンの絶対値（輪郭・細部の多さ）。
+fn edge(luma: &[f64], w: usize, h: usize, x: usize, y: usize) -> f64 {
+    let at = |x: usize, y: usize| luma[y * w + x];
+    let center = at(x, y);
+    let neighbors = [
+        at(x.saturating_sub(1), y),
+        at((x + 1).min(w - 1), y),
+        at(x, y.saturating_sub(1)),
+        at(x, (y + 1).min(h - 1)),
+    ];
+    (center * 4.0 - neighbors.iter().sum::<f64>()).abs()
+}
+
+/// 彩度。暗部・明部のノイズを拾わないよう中間の明るさほど重みを大きくする。
+fn saturation(rgb: [f64; 3], luma: f64) -> f64 {
+    let max = rgb[0].max(rgb[1]).max(rgb[2]);
+    let min = rgb[0].min(rgb[1]).min(rgb[2]);
+    if max <= 0.0 {
+        return 0.0;
+    }
+    let brightness_weight = 1.0 - (luma - 0.5).abs() * 2.0;
+    (max - min) / max * brightness_weight
+}
+
+/// 肌色らしさ（0.0〜1.0）。
+fn skin(rgb: [f64; 3], luma: f64) -> f64 {
+    if !(SKIN_LUMA_RANGE.0..=SKIN_LUMA_RANGE.1).contains(&luma) {
+        return 0.0;
+    }
+    let norm = (rgb[0] * rgb[0] + rgb[1] * rgb[1] + rgb[2] * rgb[2]).sqrt();
+    if norm <= 0.0 {
+        return 0.0;
+    }
+    let distance = (0..3)
+        .map(|c| (rgb[c] / norm - SKIN_COLOR[c]).powi(2))
+        .sum::<f64>()
+        .sqrt();
+    (1.0 - distance / (1.0 - SKIN_THRESHOLD)).max(0.0)
+}
+
+/// 任意の矩形内の合計を O(1) で求めるための累積和テーブル。
+struct SummedAreaTable {
+    stride: usize,
+    values: Vec<f64>,
+}
+
+impl SummedAreaTable {
+    fn new(scores: &[f64], w: usize, h: usize) -> Self {
+        let stride = w + 1;
+        let mut values = vec![0.0; stride * (h + 1)];
+        for y in 0..h {
+            let mut row_sum = 0.0;
+            for x in 0..w {
+                row_sum += scores[y * w + x];
+                values[(y + 1) * stride + x + 1] = values[y * stride + x + 1] + row_sum;
+            }
+        }
+        Self { stride, values }
+    }
+
+    fn sum(&self, x: usize, y: usize, w: usize, h: usize) -> f64 {
+        let at = |x: usize, y: usize| self.values[y * self.stride + x];
+        at(x + w, y + h) - at(x, y + h) - at(x + w, y) + at(x, y)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `detail` の列だけ白黒の市松模様、それ以外は灰色の画像。
+    fn image_with_detail(width: u32, height: u32, detail: impl Fn(u32) -> bool) -> DynamicImage {
+        DynamicImage::ImageRgb8(RgbImage::from_fn(width, height, |x, y| {
+            if detail(x) {
+                image::Rgb([((x + y) % 2 * 255) as u8; 3])
+            } else {
+                image::Rgb([128; 3])
+            }
+        }))
+    }
+
+    #[test]
+    fn search_offsets_include_the_last_position() {
+        assert_eq!(search_offsets(4).collect::<Vec<_>>(), [0, 2, 4]);
+        assert_eq!(search_offsets(5).collect::<Vec<_>>(), [0, 2, 4, 5]);
+        assert_eq!(search_offsets(0).collect::<Vec<_>>(), [0]);
+    }
+
+    #[test]
+    fn detail_at_an_edge_pulls_the_crop_to_that_edge() {
+        // 解析画像と同じ大きさにして、端の位置（256 - 63 = 193）が刻みから外れるようにする
+        let right = image_with_detail(256, 64, |x| x >= 224);
+        assert_eq!(find_best_crop(&right, (63.0, 64.0)), (193.0, 0.0));
+
+        let left = image_with_detail(256, 64, |x| x < 32);
+        assert_eq!(find_best_crop(&left, (63.0, 64.0)), (0.0, 0.0));
+
+        // 原本が解析画像より大きくても、端に寄せた窓は原本の端に合わせる
+        let large = image_with_detail(1000, 250, |x| x >= 900);
+        assert_eq!(find_best_crop(&large, (250.0, 250.0)), (750.0, 0.0));
+    }
+}
@@ -1,6 +1,87 @@
+//! 原本・変換結果を読み書きするストレージ。
+//!
+//! 本番は Storage Proxy Worker 経由で B2 にアクセスする。Worker や Cloudflare Access の
+//! 認証情報なしでローカル実行・テストできるよう、ローカルのディレクトリとメモリ上の
+//! バックエンドも用意し、STORAGE_BACKEND で切り替える。
+
+mod local;
+mod memory;
+
+use std::future::Future;
+use std::ops::Range;
+use std::pin::Pin;
+use std::sync::Arc;
+
 use bytes::Bytes;
 use reqwest::Client;
 
+pub use local::LocalStorage;
+pub use memory::MemoryStorage;
+
+/// `Storage` のメソッドが返す Future（`dyn Storage` として扱えるよう Box に入れる）。
+pub type StorageFuture<'a, T> = Pin<Box<dyn Future<Output = Result<T, StorageError>> + Send + 'a>>;
+
+/// オブジェクトストレージのバックエンド。
+pub trait Storage: Send + Sync {
+    /// オブジェクトを取得する。`if_none_match` の ETag と一致すれば本文を返さない。
+    fn get<'a>(
+        &'a self,
+        key: &'a str,
+        if_none_match: Option<&'a str>,
+    ) -> StorageFuture<'a, ObjectResponse>;
+
+    /// オブジェクトの ETag を取得する（本文は取得しない）。存在しなければ `NotFound`。
+    fn head<'a>(&'a self, key: &'a str) -> StorageFuture<'a, Option<String>>;
+
+    /// オブジェクトの `range` のバイト範囲を取得する。範囲が末尾を超える分は切り詰める。
+    fn get_range<'a>(&'a self, key: &'a str, range: Range<u64>) -> StorageFuture<'a, Bytes>;
+
+    /// オブジェクトを書き込む（既存のものは置き換える）。
+    fn put<'a>(&'a self, key: &'a str, data: Bytes, content_type: &'a str)
+    -> StorageFuture<'a, ()>;
+
+    /// `prefix` で始まるキーを一覧する。
+    fn list<'a>(&'a self, prefix: &'a str) -> StorageFuture<'a, Vec<String>>;
+}
+
+/// 環境変数からストレージのバックエンドを作成する。
+///
+/// 任意の環境変数:
+/// - STORAGE_BACKEND: `proxy`（デフォルト）/ `local` / `memory`
+/// - STORAGE_LOCAL_DIR: `local` のときのルートディレクトリ（必須）
+///
+/// `proxy` の場合は `StorageProxyClient::from_env` の環境変数も必須。
+pub fn from_env() -> Result<Arc<dyn Storage>, String> {
+    let backend = std::env::var("STORAGE_BACKEND").unwrap_or_else(|_| "proxy".to_string());
+    match backend.to_lowercase().as_str() {
+        "proxy" => Ok(Arc::new(StorageProxyClient::from_env()?)),
+        "local" => {
+            let dir = std::env::var("STORAGE_LOCAL_DIR")
+                .map_err(|_| "STORAGE_LOCAL_DIR is not set".to_string())?;
+            tracing::info!(dir = %dir, "using local storage backend");
+            Ok(Arc::new(LocalStorage::new(dir)))
+        }
+        "memory" => {
+            tracing::warn!("using in-memory storage backend (objects are lost on restart)");
+            Ok(Arc::new(MemoryStorage::new()))
+        }
+        other => Err(format!(
+            "unsupported STORAGE_BACKEND '{other}'. supported: proxy, local, memory"
+        )),
+    }
+}
+
+/// バイト範囲を `len` バイトのオブジェクトに収まるよう切り詰める。開始位置が末尾以降ならエラー。
+fn clamp_range(key: &str, range: Range<u64>, len: u64) -> Result<Range<u64>, StorageError> {
+    if range.start >= len || range.start >= range.end {
+        return Err(StorageError::Internal(format!(
+            "range {}..{} not satisfiable for {key} ({len} bytes)",
+            range.start, range.end
+        )));
+    }
+    Ok(range.start..range.end.min(len))
+}
+
 #[derive(Clone)]
 pub struct StorageProxyClient {
     client: Client,
@@ -9,7 +90,7 @@ pub struct StorageProxyClient {
     cf_access_client_secret: String,
 }
 
-#[derive(Debug, thiserror::Error)]
+#[derive(Debug, Clone, thiserror::Error)]
 pub enum StorageError {
     #[error("object not found: {key}")]
     NotFound { key: String },
@@ -21,6 +102,16 @@ pub enum StorageError {
     Internal(String),
 }
 
+/// Storage Proxy Worker から取得したオブジェクト。
+pub enum ObjectResponse {
+    /// 指定した ETag から更新されていない（304 Not Modified）
+    NotModified,
+    Modified {
+        data: Bytes,
+        etag: Option<String>,
+        content_type: Option<String>,
+    },
+}
 
 impl StorageProxyClient {
     /// 環境変数から StorageProxyClient を作成する。
@@ -48,19 +139,30 @@ impl StorageProxyClient {
     }
 
     /// キーを指定して Storage Proxy Worker からオブジェクトを取得する。
-    pub async fn get_object(&self, key: &str) -> Result<Bytes, StorageError> {
+    ///
+    /// `if_none_match` を指定すると条件付きで取得し、ETag が一致すれば本文をダウンロードしない。
+    pub async fn get_object(
+        &self,
+        key: &str,
+        if_none_match: Option<&str>,
+    ) -> Result<ObjectResponse, StorageError> {
         let url = format!("{}/{}", self.base_url, key);
 
-        let response = self
+        let mut request = self
             .client
             .get(&url)
             .header("CF-Access-Client-Id", &self.cf_access_client_id)
-            .header("CF-Access-Client-Secret", &self.cf_access_client_secret)
+            .header("CF-Access-Client-Secret", &self.cf_access_client_secret);
+        if let Some(etag) = if_none_match {
+            request = request.header(reqwest::header::IF_NONE_MATCH, etag);
+        }
+        let response = request
             .send()
             .await
             .map_err(|e| StorageError::Internal(e.to_string()))?;
 
         match response.status() {
+            reqwest::StatusCode::NOT_MODIFIED => return Ok(ObjectResponse::NotModified),
             status if status.is_success() => {}
             reqwest::StatusCode::NOT_FOUND => {
                 return Err(StorageError::NotFound {
@@ -79,6 +181,16 @@ impl StorageProxyClient {
             }
         }
 
+        let etag = response
+            .headers()
+            .get(reqwest::header::ETAG)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string);
+        let content_type = response
+            .headers()
+            .get(reqwest::header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string);
         let data = response
             .bytes()
             .await
@@ -97,6 +209,230 @@ impl StorageProxyClient {
             "received data from Storage Proxy"
         );
 
-        Ok(data)
+        Ok(ObjectResponse::Modified {
+            data,
+            etag,
+            content_type,
+        })
+    }
+
+    /// オブジェクトの ETag を取得する（本文はダウンロードしない）。
+    pub async fn head_object(&self, key: &str) -> Result<Option<String>, StorageError> {
+        let url = format!("{}/{}", self.base_url, key);
+
+        let response = self
+            .client
+            .head(&url)
+            .header("CF-Access-Client-Id", &self.cf_access_client_id)
+            .header("CF-Access-Client-Secret", &self.cf_access_client_secret)
+            .send()
+            .await
+            .map_err(|e| StorageError::Internal(e.to_string()))?;
+
+        Self::check_status(key, response.status())?;
+        Ok(response
+            .headers()
+            .get(reqwest::header::ETAG)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string))
+    }
+
+    /// オブジェクトを書き込む。Storage Proxy Worker が書き込みを許可するのは `variants/` 以下のみ。
+    pub async fn put_object(
+        &self,
+        key: &str,
+        data: Bytes,
+        content_type: &str,
+    ) -> Result<(), StorageError> {
+        let url = format!("{}/{}", self.base_url, key);
+
+        let response = self
+            .client
+            .put(&url)
+            .header("CF-Access-Client-Id", &self.cf_access_client_id)
+            .header("CF-Access-Client-Secret", &self.cf_access_client_secret)
+            .header(reqwest::header::CONTENT_TYPE, content_type)
+            .body(data)
+            .send()
+            .await
+            .map_err(|e| StorageError::Internal(e.to_string()))?;
+
+        Self::check_status(key, response.status())
+    }
+
+    /// バイト範囲を指定してオブジェクトを取得する（Range リクエスト）。
+    pub async fn get_object_range(
+        &self,
+        key: &str,
+        range: Range<u64>,
+    ) -> Result<Bytes, StorageError> {
+        if range.start >= range.end {
+            return Err(StorageError::Internal(format!(
+                "empty range {}..{} for {key}",
+                range.start, range.end
+            )));
+        }
+        let url = format!("{}/{}", self.base_url, key);
+
+        let response = self
+            .client
+            .get(&url)
+            .header("CF-Access-Client-Id", &self.cf_access_client_id)
+            .header("CF-Access-Client-Secret", &self.cf_access_client_secret)
+            .header(
+                reqwest::header::RANGE,
+                format!("bytes={}-{}", range.start, range.end - 1),
+            )
+            .send()
+            .await
+            .map_err(|e| StorageError::Internal(e.to_string()))?;
+
+        let status = response.status();
+        Self::check_status(key, status)?;
+        let data = response
+            .bytes()
+            .await
+            .map_err(|e| StorageError::Internal(e.to_string()))?;
+
+        if status == reqwest::StatusCode::PARTIAL_CONTENT {
+            return Ok(data);
+        }
+        // Range が無視されて全体が返った場合は必要な範囲だけ切り出す
+        let range = clamp_range(key, range, data.len() as u64)?;
+        Ok(data.slice(range.start as usize..range.end as usize))
+    }
+
+    /// `prefix` で始まるキーを一覧する。
+    ///
+    /// Storage Proxy Worker が S3 互換の ListObjectsV2 をそのまま中継するため、
+    /// Worker 側で ALLOW_LIST_BUCKET を有効にしておく必要がある。
+    pub async fn list_objects(&self, prefix: &str) -> Result<Vec<String>, StorageError> {
+        let mut keys = Vec::new();
+        let mut continuation_token: Option<String> = None;
+
+        loop {
+            let mut url = format!(
+                "{}/?list-type=2&prefix={}",
+                self.base_url,
+                urlencoding::encode(prefix)
+            );
+            if let Some(token) = &continuation_token {
+                url.push_str("&continuation-token=");
+                url.push_str(&urlencoding::encode(token));
+            }
+
+            let response = self
+                .client
+                .get(&url)
+                .header("CF-Access-Client-Id", &self.cf_access_client_id)
+                .header("CF-Access-Client-Secret", &self.cf_access_client_secret)
+                .send()
+                .await
+                .map_err(|e| StorageError::Internal(e.to_string()))?;
+            Self::check_status(prefix, response.status())?;
+            let xml = response
+                .text()
+                .await
+                .map_err(|e| StorageError::Internal(e.to_string()))?;
+
+            keys.extend(xml_elements(&xml, "Key"));
+            continuation_token = xml_elements(&xml, "NextContinuationToken")
+                .into_iter()
+                .next();
+            if continuation_token.is_none() {
+                return Ok(keys);
+            }
+        }
+    }
+
+    fn check_status(key: &str, status: reqwest::StatusCode) -> Result<(), StorageError> {
+        match status {
+            status if status.is_success() => Ok(()),
+            reqwest::StatusCode::NOT_FOUND => Err(StorageError::NotFound {
+                key: key.to_string(),
+            }),
+            reqwest::StatusCode::FORBIDDEN => {
+                tracing::error!(key = %key, "access denied by Storage Proxy");
+                Err(StorageError::Forbidden)
+            }
+            status => {
+                tracing::error!(key = %key, status = %status, "unexpected response from Storage Proxy");
+                Err(StorageError::Internal(format!(
+                    "unexpected status: {status}"
+                )))
+            }
+        }
+    }
+}
+
+impl Storage for StorageProxyClient {
+    fn get<'a>(
+        &'a self,
+        key: &'a str,
+        if_none_match: Option<&'a str>,
+    ) -> StorageFuture<'a, ObjectResponse> {
+        Box::pin(self.get_object(key, if_none_match))
+    }
+
+    fn head<'a>(&'a self, key: &'a str) -> StorageFuture<'a, Option<String>> {
+        Box::pin(self.head_object(key))
+    }
+
+    fn get_range<'a>(&'a self, key: &'a str, range: Range<u64>) -> StorageFuture<'a, Bytes> {
+        Box::pin(self.get_object_range(key, range))
+    }
+
+    fn put<'a>(
+        &'a self,
+        key: &'a str,
+        data: Bytes,
+        content_type: &'a str,
+    ) -> StorageFuture<'a, ()> {
+        Box::pin(self.put_object(key, data, content_type))
+    }
+
+    fn list<'a>(&'a self, prefix: &'a str) -> StorageFuture<'a, Vec<String>> {
+        Box::pin(self.list_objects(prefix))
+    }
+}
+
+/// S3 の XML レスポンスから `tag` 要素のテキストを順に取り出す（属性・入れ子のない要素のみ）。
+fn xml_elements(xml: &str, tag: &str) -> Vec<String> {
+    let open = format!("<{tag}>");
+    let close = format!("</{tag}>");
+    xml.split(open.as_str())
+        .skip(1)
+        .filter_map(|rest| rest.split_once(close.as_str()))
+        .map(|(value, _)| {
+            value
+                .replace("&lt;", "<")
+                .replace("&gt;", ">")
+                .replace("&quot;", "\"")
+                .replace("&apos;", "'")
+                .replace("&amp;", "&")
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clamps_range_to_object_length() {
+        assert_eq!(clamp_range("a", 2..5, 10).unwrap(), 2..5);
+        assert_eq!(clamp_range("a", 8..100, 10).unwrap(), 8..10);
+        assert!(clamp_range("a", 10..12, 10).is_err());
+        assert!(clamp_range("a", 3..3, 10).is_err());
+    }
+
+    #[test]
+    fn extracts_xml_elements_in_order() {
+        let xml = "<ListBucketResult><Contents><Key>variants/a</Key></Contents>\
+                   <Contents><Key>variants/b&amp;c</Key></Contents>\
+                   <NextContinuationToken>t&lt;1&gt;</NextContinuationToken></ListBucketResult>";
+        assert_eq!(xml_elements(xml, "Key"), ["variants/a", "variants/b&c"]);
+        assert_eq!(xml_elements(xml, "NextContinuationToken"), ["t<1>"]);
+        assert!(xml_elements(xml, "Prefix").is_empty());
     }
 }
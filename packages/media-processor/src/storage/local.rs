@@ -0,0 +1,241 @@
+//! ローカルのディレクトリをストレージとして使うバックエンド（ローカル実行用）。
+//!
+//! キーをルートからの相対パスとして扱う。Content-Type は保存せず、取得時に内容から判定する。
+
+use std::io::SeekFrom;
+use std::ops::Range;
+use std::path::{Component, Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use bytes::Bytes;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+use super::{ObjectResponse, Storage, StorageError, StorageFuture, clamp_range};
+use crate::fs::write_atomic;
+
+/// 書き込み中の一時ファイルを置くディレクトリ。`.` で始まる名前は一覧に含めない。
+const TMP_DIR: &str = ".tmp";
+
+#[derive(Clone)]
+pub struct LocalStorage {
+    root: PathBuf,
+}
+
+impl LocalStorage {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self { root: root.into() }
+    }
+
+    /// キーをファイルのパスに変換する。ルートの外を指すキーは拒否する。
+    fn path(&self, key: &str) -> Result<PathBuf, StorageError> {
+        let relative = Path::new(key);
+        if !relative
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)))
+        {
+            return Err(StorageError::Internal(format!("invalid key: {key}")));
+        }
+        Ok(self.root.join(relative))
+    }
+
+    async fn get_object(
+        &self,
+        key: &str,
+        if_none_match: Option<&str>,
+    ) -> Result<ObjectResponse, StorageError> {
+        let path = self.path(key)?;
+        let metadata = tokio::fs::metadata(&path)
+            .await
+            .map_err(|e| io_error(key, e))?;
+        let etag = etag(&metadata);
+        if if_none_match == Some(etag.as_str()) {
+            return Ok(ObjectResponse::NotModified);
+        }
+
+        let data = Bytes::from(tokio::fs::read(&path).await.map_err(|e| io_error(key, e))?);
+        let content_type = image::guess_format(&data)
+            .ok()
+            .map(|format| format.to_mime_type().to_string());
+        Ok(ObjectResponse::Modified {
+            data,
+            etag: Some(etag),
+            content_type,
+        })
+    }
+
+    async fn head_object(&self, key: &str) -> Result<Option<String>, StorageError> {
+        let metadata = tokio::fs::metadata(self.path(key)?)
+            .await
+            .map_err(|e| io_error(key, e))?;
+        if !metadata.is_file() {
+            return Err(StorageError::NotFound {
+                key: key.to_string(),
+            });
+        }
+        Ok(Some(etag(&metadata)))
+    }
+
+    async fn get_object_range(&self, key: &str, range: Range<u64>) -> Result<Bytes, StorageError> {
+        let mut file = tokio::fs::File::open(self.path(key)?)
+            .await
+            .map_err(|e| io_error(key, e))?;
+        let len = file.metadata().await.map_err(|e| io_error(key, e))?.len();
+        let range = clamp_range(key, range, len)?;
+
+        file.seek(SeekFrom::Start(range.start))
+            .await
+            .map_err(|e| io_error(key, e))?;
+        let mut data = vec![0; (range.end - range.start) as usize];
+        file.read_exact(&mut data)
+            .await
+            .map_err(|e| io_error(key, e))?;
+        Ok(Bytes::from(data))
+    }
+
+    async fn put_object(&self, key: &str, data: Bytes) -> Result<(), StorageError> {
+        write_atomic(&self.path(key)?, &self.root.join(TMP_DIR), &data)
+            .await
+            .map_err(|e| io_error(key, e))
+    }
+
+    async fn list_objects(&self, prefix: &str) -> Result<Vec<String>, StorageError> {
+        let root = self.root.clone();
+        let prefix = prefix.to_string();
+        tokio::task::spawn_blocking(move || {
+            let mut keys = Vec::new();
+            collect_keys(&root, "", &prefix, &mut keys)
+                .map_err(|e| StorageError::Internal(e.to_string()))?;
+            keys.sort();
+            Ok(keys)
+        })
+        .await
+        .map_err(|e| StorageError::Internal(e.to_string()))?
+    }
+}
+
+impl Storage for LocalStorage {
+    fn get<'a>(
+        &'a self,
+        key: &'a str,
+        if_none_match: Option<&'a str>,
+    ) -> StorageFuture<'a, ObjectResponse> {
+        Box::pin(self.get_object(key, if_none_match))
+    }
+
+    fn head<'a>(&'a self, key: &'a str) -> StorageFuture<'a, Option<String>> {
+        Box::pin(self.head_object(key))
+    }
+
+    fn get_range<'a>(&'a self, key: &'a str, range: Range<u64>) -> StorageFuture<'a, Bytes> {
+        Box::pin(self.get_object_range(key, range))
+    }
+
+    fn put<'a>(
+        &'a self,
+        key: &'a str,
+        data: Bytes,
+        _content_type: &'a str,
+    ) -> StorageFuture<'a, ()> {
+        Box::pin(self.put_object(key, data))
+    }
+
+    fn list<'a>(&'a self, prefix: &'a str) -> StorageFuture<'a, Vec<String>> {
+        Box::pin(self.list_objects(prefix))
+    }
+}
+
+/// `dir` 以下のファイルのうち、キーが `prefix` で始まるものを `keys` に追加する。
+fn collect_keys(
+    dir: &Path,
+    dir_key: &str,
+    prefix: &str,
+    keys: &mut Vec<String>,
+) -> std::io::Result<()> {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+
+    for entry in entries {
+        let entry = entry?;
+        let Ok(name) = entry.file_name().into_string() else {
+            continue;
+        };
+        if name.starts_with('.') {
+            continue;
+        }
+        let key = format!("{dir_key}{name}");
+        if entry.file_type()?.is_dir() {
+            // prefix と無関係なディレクトリは辿らない
+            let dir_prefix = format!("{key}/");
+            if dir_prefix.starts_with(prefix) || prefix.starts_with(&dir_prefix) {
+                collect_keys(&entry.path(), &dir_prefix, prefix, keys)?;
+            }
+        } else if key.starts_with(prefix) {
+            keys.push(key);
+        }
+    }
+    Ok(())
+}
+
+/// サイズと更新日時から ETag を作る（書き込みのたびに変わる）。
+fn etag(metadata: &std::fs::Metadata) -> String {
+    let modified = metadata
+        .modified()
+        .ok()
+        .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |duration| duration.as_nanos());
+    format!("\"{:x}-{:x}\"", metadata.len(), modified)
+}
+
+fn io_error(key: &str, err: std::io::Error) -> StorageError {
+    match err.kind() {
+        // ディレクトリを指すキーも存在しないオブジェクトとして扱う
+        std::io::ErrorKind::NotFound | std::io::ErrorKind::IsADirectory => StorageError::NotFound {
+            key: key.to_string(),
+        },
+        _ => StorageError::Internal(err.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn reads_ranges_and_lists_by_prefix() {
+        let root = std::env::temp_dir().join(format!("local-storage-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        let storage = LocalStorage::new(&root);
+        for key in ["variants/b", "variants/a", "originals/a", "variantsx"] {
+            storage
+                .put(key, Bytes::from_static(b"0123456789"), "image/webp")
+                .await
+                .unwrap();
+        }
+        // 書き込み中の一時ファイルは一覧に含めない
+        std::fs::write(root.join(TMP_DIR).join("1.0"), b"partial").unwrap();
+
+        assert_eq!(
+            storage.list("variants/").await.unwrap(),
+            ["variants/a", "variants/b"]
+        );
+        assert_eq!(
+            storage.list("").await.unwrap(),
+            ["originals/a", "variants/a", "variants/b", "variantsx"]
+        );
+        assert!(storage.list("thumbnails/").await.unwrap().is_empty());
+
+        assert_eq!(storage.get_range("variants/a", 2..5).await.unwrap(), "234");
+        assert_eq!(storage.get_range("variants/a", 8..100).await.unwrap(), "89");
+        assert!(storage.get_range("variants/a", 10..12).await.is_err());
+        assert!(matches!(
+            storage.get_range("variants/c", 0..1).await,
+            Err(StorageError::NotFound { .. })
+        ));
+        assert!(storage.get_range("../a", 0..1).await.is_err());
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
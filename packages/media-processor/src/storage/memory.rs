@@ -0,0 +1,153 @@
+//! メモリ上に保持するストレージのバックエンド（テスト・一時的な実行用）。起動時は空。
+
+use std::collections::BTreeMap;
+use std::ops::Range;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use bytes::Bytes;
+
+use super::{ObjectResponse, Storage, StorageError, StorageFuture, clamp_range};
+
+#[derive(Clone, Default)]
+pub struct MemoryStorage {
+    /// キーの順に並べ、接頭辞での一覧を範囲検索で済ませる
+    objects: Arc<Mutex<BTreeMap<String, MemoryObject>>>,
+    /// ETag に使う書き込みの通し番号
+    version: Arc<AtomicU64>,
+}
+
+struct MemoryObject {
+    data: Bytes,
+    content_type: String,
+    etag: String,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, BTreeMap<String, MemoryObject>> {
+        crate::sync::lock(&self.objects)
+    }
+
+    fn get_object(
+        &self,
+        key: &str,
+        if_none_match: Option<&str>,
+    ) -> Result<ObjectResponse, StorageError> {
+        let objects = self.lock();
+        let object = objects.get(key).ok_or_else(|| not_found(key))?;
+        if if_none_match == Some(object.etag.as_str()) {
+            return Ok(ObjectResponse::NotModified);
+        }
+        Ok(ObjectResponse::Modified {
+            data: object.data.clone(),
+            etag: Some(object.etag.clone()),
+            content_type: Some(object.content_type.clone()),
+        })
+    }
+
+    fn get_object_range(&self, key: &str, range: Range<u64>) -> Result<Bytes, StorageError> {
+        let objects = self.lock();
+        let data = &objects.get(key).ok_or_else(|| not_found(key))?.data;
+        let range = clamp_range(key, range, data.len() as u64)?;
+        Ok(data.slice(range.start as usize..range.end as usize))
+    }
+
+    fn put_object(&self, key: &str, data: Bytes, content_type: &str) {
+        let version = self.version.fetch_add(1, Ordering::Relaxed);
+        self.lock().insert(
+            key.to_string(),
+            MemoryObject {
+                data,
+                content_type: content_type.to_string(),
+                etag: format!("\"{version:x}\""),
+            },
+        );
+    }
+
+    fn list_objects(&self, prefix: &str) -> Vec<String> {
+        self.lock()
+            .range(prefix.to_string()..)
+            .map(|(key, _)| key)
+            .take_while(|key| key.starts_with(prefix))
+            .cloned()
+            .collect()
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn get<'a>(
+        &'a self,
+        key: &'a str,
+        if_none_match: Option<&'a str>,
+    ) -> StorageFuture<'a, ObjectResponse> {
+        Box::pin(async move { self.get_object(key, if_none_match) })
+    }
+
+    fn head<'a>(&'a self, key: &'a str) -> StorageFuture<'a, Option<String>> {
+        Box::pin(async move {
+            let objects = self.lock();
+            let object = objects.get(key).ok_or_else(|| not_found(key))?;
+            Ok(Some(object.etag.clone()))
+        })
+    }
+
+    fn get_range<'a>(&'a self, key: &'a str, range: Range<u64>) -> StorageFuture<'a, Bytes> {
+        Box::pin(async move { self.get_object_range(key, range) })
+    }
+
+    fn put<'a>(
+        &'a self,
+        key: &'a str,
+        data: Bytes,
+        content_type: &'a str,
+    ) -> StorageFuture<'a, ()> {
+        Box::pin(async move {
+            self.put_object(key, data, content_type);
+            Ok(())
+        })
+    }
+
+    fn list<'a>(&'a self, prefix: &'a str) -> StorageFuture<'a, Vec<String>> {
+        Box::pin(async move { Ok(self.list_objects(prefix)) })
+    }
+}
+
+fn not_found(key: &str) -> StorageError {
+    StorageError::NotFound {
+        key: key.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn reads_ranges_and_lists_by_prefix() {
+        let storage = MemoryStorage::new();
+        for key in ["variants/b", "variants/a", "originals/a", "variantsx"] {
+            storage
+                .put(key, Bytes::from_static(b"0123456789"), "image/webp")
+                .await
+                .unwrap();
+        }
+
+        assert_eq!(
+            storage.list("variants/").await.unwrap(),
+            ["variants/a", "variants/b"]
+        );
+        assert!(storage.list("thumbnails/").await.unwrap().is_empty());
+
+        assert_eq!(storage.get_range("variants/a", 2..5).await.unwrap(), "234");
+        assert_eq!(storage.get_range("variants/a", 8..100).await.unwrap(), "89");
+        assert!(storage.get_range("variants/a", 10..12).await.is_err());
+        assert!(matches!(
+            storage.get_range("variants/c", 0..1).await,
+            Err(StorageError::NotFound { .. })
+        ));
+    }
+}
@@ -0,0 +1,10 @@
+//! プロセス内で共有する状態のロック。
+
+use std::sync::{Mutex, MutexGuard};
+
+/// poison を無視して `mutex` をロックする。
+///
+/// ロック中に panic する処理を置かないため、poison されていても中身は一貫している。
+pub fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
@@ -1,12 +1,36 @@
 use bytes::Bytes;
 use exif::{In, Tag};
 use fast_image_resize::images::Image;
-use fast_image_resize::{PixelType, ResizeAlg, ResizeOptions, Resizer};
+use fast_image_resize::{
+    IntoImageView, PixelComponentMapper, PixelType, ResizeOptions, Resizer, create_srgb_mapper,
+};
+use heic::PixelLayout;
 use image::codecs::avif::AvifEncoder;
 use image::codecs::jpeg::JpegEncoder;
+use image::codecs::png::PngEncoder;
 use image::codecs::webp::WebPEncoder;
-use image::{DynamicImage, ImageFormat, ImageReader};
+use image::{
+    DynamicImage, GrayImage, ImageBuffer, ImageDecoder, ImageEncoder, ImageFormat, ImageReader,
+    RgbImage, Rgba, RgbaImage,
+};
+use jpeg_decoder::{Decoder as JpegDecoder, PixelFormat as JpegPixelFormat};
+use std::borrow::Cow;
 use std::io::Cursor;
+use std::ops::RangeInclusive;
+use std::sync::OnceLock;
+use tiff::ColorType as TiffColorType;
+use tiff::decoder::{Decoder as TiffDecoder, DecodingResult};
+use tiff::tags::Tag as TiffTag;
+
+use crate::avif;
+use crate::color::{self, ColorSpace, OutputColor, SourceProfile};
+use crate::filter::{self, MAX_SHARPEN, ResizeFilter};
+use crate::geometry::{
+    Canvas, CropLength, CropRect, Fit, Gravity, Region, ResizePlan, ResizeTarget, plan_resize,
+};
+use crate::limits::DecodeLimits;
+use crate::rotate::{self, Flip};
+use crate::smartcrop;
 
 #[derive(Debug, Clone)]
 pub struct TransformParams {
@@ -14,11 +38,155 @@ pub struct TransformParams {
     pub height: Option<u32>,
     pub format: Option<OutputFormat>,
     pub quality: Option<u8>,
+    /// マルチページ TIFF のページ番号（0 始まり、未指定時は先頭ページ）
+    pub page: Option<u32>,
+    /// WebP をロスレスでエンコードする（quality は無視される）
+    pub lossless: bool,
+    /// w と h が両方指定されたときの収め方（未指定時は Contain、ar 指定時は Cover）
+    pub fit: Option<Fit>,
+    /// Cover の切り取り位置 / Pad の配置位置
+    pub gravity: Gravity,
+    /// 出力のアスペクト比（幅 / 高さ）
+    pub aspect_ratio: Option<f64>,
+    /// リサイズ前に切り取る矩形（向き補正後・rot / flip 適用前の画像基準）
+    pub crop: Option<CropRect>,
+    /// 時計回りの回転角度（度）。EXIF Orientation の補正後に適用する
+    pub rotation: f64,
+    /// 反転方向。回転の後に適用する
+    pub flip: Option<Flip>,
+    /// 任意角度の回転・Pad で生じる余白の色（未指定時は JPEG なら白、それ以外は透明）
+    pub background: Option<Rgba<u8>>,
+    /// 出力の色空間
+    pub color_space: ColorSpace,
+    /// 線形光（ガンマ補正を外した値）でリサイズする
+    pub linear_light: bool,
+    /// リサイズの補間フィルタ（未指定時は縮小なら Lanczos3、拡大なら Mitchell）
+    pub filter: Option<ResizeFilter>,
+    /// リサイズ後に適用するアンシャープマスクの強さ（0 で無効）
+    pub sharpen: f32,
+    /// 元画像より大きいサイズへの拡大を許可する（出力の一辺は MAX_DIMENSION まで）
+    pub enlarge: bool,
+    /// AVIF のエンコード速度（1: 最も遅く高圧縮 〜 10: 最も速い）
+    pub avif_speed: u8,
 }
 
 impl TransformParams {
     pub fn needs_resize(&self) -> bool {
-        self.width.is_some() || self.height.is_some()
+        self.width.is_some() || self.height.is_some() || self.aspect_ratio.is_some()
+    }
+
+    /// ar だけでは切り取りが起きないため、fit 未指定で ar がある場合は Cover として扱う。
+    fn effective_fit(&self) -> Fit {
+        self.fit.unwrap_or(if self.aspect_ratio.is_some() {
+            Fit::Cover
+        } else {
+            Fit::Contain
+        })
+    }
+
+    fn resize_target(&self) -> ResizeTarget {
+        ResizeTarget {
+            width: self.width,
+            height: self.height,
+            fit: self.effective_fit(),
+            gravity: self.gravity,
+            aspect_ratio: self.aspect_ratio,
+            enlarge: self.enlarge,
+            max_dimension: MAX_DIMENSION,
+        }
+    }
+
+    /// 変換結果のキャッシュキー（singleflight・プロセス内 LRU・ディスクキャッシュ・`variants/` で共通）。
+    ///
+    /// 同じ出力になるパラメータ（`rot=0` と `rot=360`、`fit=inside` と `fit=contain` 等）は
+    /// 同じキーにする。保存済みの結果を無効にしたい場合（出力が変わる変更をした場合）は
+    /// CACHE_KEY_VERSION を上げる。フィールドを追加した場合はここにも追加すること。
+    pub fn cache_key(&self, object_key: &str) -> String {
+        fn opt<T: std::fmt::Display>(value: Option<T>) -> String {
+            value.map_or_else(|| "-".to_string(), |value| value.to_string())
+        }
+        // -0.0 を 0 に揃える
+        fn num(value: f64) -> String {
+            (value + 0.0).to_string()
+        }
+        fn crop_length(length: CropLength) -> String {
+            match length {
+                CropLength::Pixels(px) => px.to_string(),
+                CropLength::Percent(pct) => format!("{}%", num(pct)),
+            }
+        }
+
+        let fit = match self.effective_fit() {
+            Fit::Contain | Fit::Inside => "contain",
+            Fit::Cover => "cover",
+            Fit::Fill => "fill",
+            Fit::Outside => "outside",
+            Fit::Pad => "pad",
+        };
+        let gravity = match self.gravity {
+            Gravity::Center => "center".to_string(),
+            Gravity::North => "north".to_string(),
+            Gravity::South => "south".to_string(),
+            Gravity::East => "east".to_string(),
+            Gravity::West => "west".to_string(),
+            Gravity::NorthEast => "northeast".to_string(),
+            Gravity::NorthWest => "northwest".to_string(),
+            Gravity::SouthEast => "southeast".to_string(),
+            Gravity::SouthWest => "southwest".to_string(),
+            Gravity::Focal { x, y } => format!("focal:{},{}", num(x), num(y)),
+            Gravity::Smart => "smart".to_string(),
+        };
+        let crop = self.crop.map(|crop| {
+            [crop.x, crop.y, crop.width, crop.height]
+                .map(crop_length)
+                .join(",")
+        });
+        let flip = self.flip.map(|flip| match flip {
+            Flip::Horizontal => "h",
+            Flip::Vertical => "v",
+            Flip::Both => "hv",
+        });
+        let background = self
+            .background
+            .map(|Rgba([r, g, b, a])| format!("{r:02x}{g:02x}{b:02x}{a:02x}"));
+        let color_space = match self.color_space {
+            ColorSpace::Srgb => "srgb",
+            ColorSpace::DisplayP3 => "p3",
+            ColorSpace::Keep => "keep",
+        };
+        let filter = self.filter.map(|filter| match filter {
+            ResizeFilter::Nearest => "nearest",
+            ResizeFilter::Bilinear => "bilinear",
+            ResizeFilter::CatmullRom => "catmull-rom",
+            ResizeFilter::Mitchell => "mitchell",
+            ResizeFilter::Lanczos3 => "lanczos3",
+            ResizeFilter::Box => "box",
+        });
+
+        [
+            CACHE_KEY_VERSION.to_string(),
+            object_key.to_string(),
+            format!("w={}", opt(self.width)),
+            format!("h={}", opt(self.height)),
+            format!("f={}", opt(self.format.map(OutputFormat::extension))),
+            format!("q={}", self.quality.unwrap_or(DEFAULT_QUALITY)),
+            format!("page={}", self.page.unwrap_or(0)),
+            format!("lossless={}", self.lossless),
+            format!("fit={fit}"),
+            format!("gravity={gravity}"),
+            format!("ar={}", opt(self.aspect_ratio.map(num))),
+            format!("crop={}", opt(crop)),
+            format!("rot={}", num(self.rotation.rem_euclid(360.0))),
+            format!("flip={}", opt(flip)),
+            format!("bg={}", opt(background)),
+            format!("cs={color_space}"),
+            format!("linear={}", self.linear_light),
+            format!("filter={}", opt(filter)),
+            format!("sharpen={}", num(self.sharpen as f64)),
+            format!("enlarge={}", self.enlarge),
+            format!("speed={}", self.avif_speed),
+        ]
+        .join("|")
     }
 }
 
@@ -49,6 +217,32 @@ impl OutputFormat {
             Self::Avif => "image/avif",
         }
     }
+
+    /// キャッシュキー等に使う短い名前。
+    fn extension(self) -> &'static str {
+        match self {
+            Self::Jpeg => "jpeg",
+            Self::Png => "png",
+            Self::WebP => "webp",
+            Self::Avif => "avif",
+        }
+    }
+
+    /// `content_type` の逆変換（ディスクキャッシュから読み戻すときに使う）。
+    pub fn from_content_type(s: &str) -> Option<Self> {
+        [Self::Jpeg, Self::Png, Self::WebP, Self::Avif]
+            .into_iter()
+            .find(|format| format.content_type() == s)
+    }
+}
+
+/// デコード元のフォーマット。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SourceFormat {
+    /// image クレートでデコードしたフォーマット
+    Image(ImageFormat),
+    /// HEIF/HEIC（AVIF と同じく heic クレートでデコード）
+    Heif,
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -61,13 +255,27 @@ pub enum TransformError {
     )]
     ResolutionTooLarge { width: u32, height: u32 },
 
+    #[error("source image is too large: {0}")]
+    SourceTooLarge(String),
+
     #[error("transform failed: {0}")]
     ProcessingFailed(String),
 }
 
 const MAX_DIMENSION: u32 = 4096;
-const MAX_PIXELS: u64 = 1_000_000_000; // 1GP（実質無制限、極端な攻撃のみ防止）
+/// `TransformParams::cache_key` の形式のバージョン
+const CACHE_KEY_VERSION: &str = "v1";
 const DEFAULT_QUALITY: u8 = 80;
+/// AVIF のエンコード速度の範囲（ravif の speed）と既定値
+pub const AVIF_SPEED_RANGE: RangeInclusive<u8> = 1..=10;
+pub const DEFAULT_AVIF_SPEED: u8 = 4;
+/// 変換中に同時に存在しうる画像全体のバッファ数（デコード結果・回転等のコピー・リサイズ用の RGBA）
+const WORKING_COPIES: u64 = 3;
+
+/// HEIF コンテナの ftyp ブランド（HEVC 系・AVIF・汎用）。
+const HEVC_BRANDS: [&[u8; 4]; 6] = [b"heic", b"heix", b"heim", b"heis", b"hevc", b"hevx"];
+const AVIF_BRANDS: [&[u8; 4]; 2] = [b"avif", b"avis"];
+const GENERIC_HEIF_BRANDS: [&[u8; 4]; 2] = [b"mif1", b"msf1"];
 
 /// 指定されたパラメータに従って画像バイト列を変換する。
 ///
@@ -77,54 +285,617 @@ const DEFAULT_QUALITY: u8 = 80;
 pub fn transform(
     input: &Bytes,
     params: &TransformParams,
+    limits: &DecodeLimits,
 ) -> Result<(Bytes, &'static str), TransformError> {
     validate_params(params)?;
 
-    let orientation = read_exif_orientation(input);
-    let (img, source_format) = decode_image(input)?;
+    // デコード前にヘッダの解像度を検証し、巨大な画像のメモリを確保しない
+    let header = probe_header(input, params.page);
+    if let Some(header) = &header {
+        limits
+            .check(header.width, header.height)
+            .map_err(TransformError::SourceTooLarge)?;
+    }
+
+    let orientation = source_orientation(input);
+
+    let plan = header
+        .as_ref()
+        .and_then(|header| Some((header, plan_before_decode(params, header, orientation)?)));
+    let is_jpeg =
+        params.page.unwrap_or(0) == 0 && image::guess_format(input).ok() == Some(ImageFormat::Jpeg);
+
+    // 極小のプレビューは EXIF のサムネイルで足りれば原本をデコードしない。
+    // サムネイルはプロファイルを持たず sRGB として扱うため、広色域の原本では使わない
+    let thumbnail = match plan {
+        Some((header, plan)) if is_jpeg && !jpeg_has_non_srgb_profile(input) => {
+            decode_exif_thumbnail(input, header, &plan)
+        }
+        _ => None,
+    };
+
+    let DecodedImage {
+        image: img,
+        format: source_format,
+        color_profile,
+    } = match thumbnail {
+        // サムネイルは ICC プロファイルを持たないため sRGB とみなす
+        Some(image) => DecodedImage {
+            image,
+            format: Some(SourceFormat::Image(ImageFormat::Jpeg)),
+            color_profile: None,
+        },
+        None => {
+            // 出力が十分小さい JPEG は DCT スケーリングで縮小しながらデコードする（shrink-on-load）
+            let shrink = plan.map_or(1, |(_, plan)| jpeg_shrink_factor(&plan));
+            decode_image(input, params.page, limits, shrink)?
+        }
+    };
+    let has_alpha = img.color().has_alpha();
 
     // EXIF Orientation を適用（メタデータは再エンコードで除去されるため、ピクセルを回転）
     let img = apply_orientation(img, orientation);
-    let (src_w, src_h) = (img.width(), img.height());
 
-    validate_source_dimensions(src_w, src_h)?;
+    let output_format = determine_output_format(source_format, params.format, has_alpha);
+
+    // 原本の ICC プロファイルは出力で削除されるため、先にピクセルを出力の色空間に変換しておく
+    let (img, output_color) = color::prepare_output(
+        img,
+        color_profile,
+        params.color_space,
+        output_format == OutputFormat::Avif,
+    );
+
+    let background = params.background.unwrap_or(pad_color(output_format));
+
+    // 切り取りは向き補正後の画像を基準に、回転・リサイズより先に行う
+    let img = match params.crop {
+        Some(crop) => crop_image(img, &crop)?,
+        None => img,
+    };
+
+    // 手動の回転・反転（EXIF Orientation が欠落・誤っている画像の補正用）
+    let img = rotate::rotate(img, params.rotation, background, limits)
+        .map_err(TransformError::SourceTooLarge)?;
+    let img = match params.flip {
+        Some(flip) => rotate::flip(img, flip),
+        None => img,
+    };
+    let (src_w, src_h) = (img.width(), img.height());
 
     let resized = if params.needs_resize() {
-        let (dst_w, dst_h) =
-            calculate_contain_dimensions(src_w, src_h, params.width, params.height);
-        validate_output_dimensions(dst_w, dst_h)?;
+        let mut plan = plan_resize(src_w, src_h, &params.resize_target());
+        // smart は切り取りが発生する場合のみ、画像内容から切り取り位置を決め直す
+        if params.gravity == Gravity::Smart && !plan.source.is_full(src_w, src_h) {
+            let (left, top) =
+                smartcrop::find_best_crop(&img, (plan.source.width, plan.source.height));
+            plan.source.left = left;
+            plan.source.top = top;
+        }
+        let (out_w, out_h) = plan.output_dimensions();
+        validate_output_dimensions(out_w, out_h)?;
 
-        if dst_w != src_w || dst_h != src_h {
-            resize_image(&img, dst_w, dst_h)?
-        } else {
-            img
+        let resized =
+            if plan.source.is_full(src_w, src_h) && plan.width == src_w && plan.height == src_h {
+                img
+            } else {
+                let resized = resize_image(
+                    &img,
+                    plan.source,
+                    plan.width,
+                    plan.height,
+                    params
+                        .filter
+                        .unwrap_or(ResizeFilter::default_for(plan.is_upscale())),
+                    params.linear_light,
+                )?;
+                // 縮小でぼやけた輪郭を補う（余白を足す前に行い、余白との境界は強調しない）
+                filter::sharpen(resized, params.sharpen)
+            };
+
+        match plan.canvas {
+            Some(canvas) => pad_image(&resized, canvas, background),
+            None => resized,
         }
     } else {
         img
     };
 
-    let output_format = determine_output_format(source_format, params.format);
-
     let content_type = output_format.content_type();
     let quality = params.quality.unwrap_or(DEFAULT_QUALITY);
-    let output_bytes = encode_image(&resized, output_format, quality)?;
+    let output_bytes = encode_image(
+        &resized,
+        output_format,
+        quality,
+        params.lossless,
+        params.avif_speed,
+        &output_color,
+    )?;
 
     Ok((Bytes::from(output_bytes), content_type))
 }
 
-/// 画像バイト列をデコードし、DynamicImage と元のフォーマットを返す。
-fn decode_image(input: &Bytes) -> Result<(DynamicImage, Option<ImageFormat>), TransformError> {
-    let reader = ImageReader::new(Cursor::new(input.as_ref()))
+/// デコード前にヘッダだけを読み、変換に必要なメモリ量（バイト）を見積もる。
+///
+/// 変換の途中で最も大きくなるバッファ（デコード結果・任意角度の回転で広げたキャンバス・
+/// リサイズ結果・pad の余白を含むキャンバス）のバイト数 × 作業コピー数。RGBA への変換を伴う
+/// 段階は、チャンネルのバイト数を保ったままアルファを加えたサイズで見積もる。
+/// ヘッダを読めない場合は None（デコード自体が失敗するため予算の対象外とする）。
+pub fn estimate_memory(input: &Bytes, params: &TransformParams) -> Option<u64> {
+    let header = probe_header(input, params.page)?;
+    let area = |width: u32, height: u32| width as u64 * height as u64;
+    // リサイズ・エンコードで RGBA に変換する場合を考慮して、1 ピクセル最低 4 バイト
+    let native_bytes = header.bytes_per_pixel.max(4);
+    let rgba_bytes = 4 * header.bytes_per_channel;
+    let mut largest = area(header.width, header.height) * native_bytes;
+
+    let (mut width, mut height) = if source_orientation(input) >= 5 {
+        (header.height, header.width)
+    } else {
+        (header.width, header.height)
+    };
+    if let Some((_, _, crop_w, crop_h)) = params.crop.and_then(|crop| crop.resolve(width, height)) {
+        (width, height) = (crop_w, crop_h);
+    }
+    let degrees = params.rotation.rem_euclid(360.0);
+    if degrees.rem_euclid(90.0) != 0.0 {
+        (width, height) = rotate::rotated_dimensions(width, height, degrees);
+        largest = largest.max(area(width, height) * rgba_bytes);
+    } else if degrees == 90.0 || degrees == 270.0 {
+        (width, height) = (height, width);
+    }
+
+    if params.needs_resize() {
+        let plan = plan_resize(width, height, &params.resize_target());
+        largest = largest.max(area(plan.width, plan.height) * native_bytes.max(rgba_bytes));
+        // pad の余白を付けたキャンバスは RGBA8
+        if let Some(canvas) = plan.canvas {
+            largest = largest.max(area(canvas.width, canvas.height) * 4);
+        }
+    }
+    Some(largest * WORKING_COPIES)
+}
+
+/// 原本の EXIF Orientation。HEIF/AVIF はデコーダが irot/imir を適用済みのため、
+/// EXIF Orientation は重ねて適用しない（常に 1）。
+fn source_orientation(input: &[u8]) -> u32 {
+    if detect_heif_container(input).is_some() {
+        1
+    } else {
+        read_exif_orientation(input)
+    }
+}
+
+/// ヘッダから読んだ原本の解像度と、デコード結果の 1 ピクセル・1 チャンネルのバイト数。
+struct SourceHeader {
+    width: u32,
+    height: u32,
+    bytes_per_pixel: u64,
+    bytes_per_channel: u64,
+}
+
+/// ピクセルデータをデコードせずにヘッダだけを読む。
+fn probe_header(input: &[u8], page: Option<u32>) -> Option<SourceHeader> {
+    let (width, height, bytes_per_pixel, bytes_per_channel) =
+        if detect_heif_container(input).is_some() {
+            let info = heic::ImageInfo::from_bytes(input).ok()?;
+            let bytes_per_pixel = if info.has_alpha { 4 } else { 3 };
+            (info.width, info.height, bytes_per_pixel, 1)
+        } else {
+            let reader = ImageReader::new(Cursor::new(input))
+                .with_guessed_format()
+                .ok()?;
+            match page {
+                Some(page) if page > 0 && reader.format() == Some(ImageFormat::Tiff) => {
+                    let mut decoder = TiffDecoder::new(Cursor::new(input)).ok()?;
+                    decoder.seek_to_image(page as usize).ok()?;
+                    let (width, height) = decoder.dimensions().ok()?;
+                    // decode_tiff_page が扱うのは 8/16bit のみで、4 バイトを超えるのは 16bit のカラーだけ
+                    let (bytes_per_pixel, bytes_per_channel) = match decoder.colortype().ok()? {
+                        TiffColorType::RGB(16) | TiffColorType::RGBA(16) => (8, 2),
+                        _ => (4, 1),
+                    };
+                    (width, height, bytes_per_pixel, bytes_per_channel)
+                }
+                _ => {
+                    let decoder = reader.into_decoder().ok()?;
+                    let (width, height) = decoder.dimensions();
+                    let color_type = decoder.color_type();
+                    let bytes_per_pixel = color_type.bytes_per_pixel() as u64;
+                    let bytes_per_channel = bytes_per_pixel / color_type.channel_count() as u64;
+                    (width, height, bytes_per_pixel, bytes_per_channel)
+                }
+            }
+        };
+
+    Some(SourceHeader {
+        width,
+        height,
+        bytes_per_pixel,
+        bytes_per_channel,
+    })
+}
+
+/// デコード結果。
+struct DecodedImage {
+    image: DynamicImage,
+    format: Option<SourceFormat>,
+    /// 埋め込み ICC プロファイル（HEIF/AVIF は nclx の色域から作ったものを含む）
+    color_profile: Option<SourceProfile>,
+}
+
+/// 画像バイト列をデコードし、DynamicImage と元のフォーマット・色空間を返す。
+///
+/// `page` はマルチページ TIFF でのみ有効（他のフォーマットでは先頭ページ以外を指定するとエラー）。
+/// `limits` を超えるメモリ確保が必要な場合は確保前に `SourceTooLarge` を返す。
+/// `shrink` が 1 より大きい場合、JPEG は 1/`shrink` に縮小してデコードする。
+fn decode_image(
+    input: &Bytes,
+    page: Option<u32>,
+    limits: &DecodeLimits,
+    shrink: u32,
+) -> Result<DecodedImage, TransformError> {
+    let page = page.unwrap_or(0);
+
+    // image クレートの avif 機能はエンコード専用のため、AVIF も HEIF と同じデコーダで読む
+    if let Some(source_format) = detect_heif_container(input) {
+        reject_page_param(page)?;
+        let (image, color_profile) = decode_heif(input, limits)?;
+        return Ok(DecodedImage {
+            image,
+            format: Some(source_format),
+            color_profile,
+        });
+    }
+
+    let mut reader = ImageReader::new(Cursor::new(input.as_ref()))
         .with_guessed_format()
         .map_err(|e| TransformError::ProcessingFailed(format!("failed to guess format: {e}")))?;
+    let mut image_limits = limits.image_limits();
+    reader.limits(image_limits.clone());
+
+    let source_format = reader.format().map(SourceFormat::Image);
 
-    let source_format = reader.format();
+    if page > 0 {
+        if reader.format() != Some(ImageFormat::Tiff) {
+            reject_page_param(page)?;
+        }
+        // image クレートの TIFF デコーダは先頭ページしか読めないため tiff クレートを直接使う
+        let (image, color_profile) = decode_tiff_page(input, page, limits)?;
+        return Ok(DecodedImage {
+            image,
+            format: source_format,
+            color_profile,
+        });
+    }
+
+    if shrink > 1
+        && reader.format() == Some(ImageFormat::Jpeg)
+        && let Some((image, icc)) = decode_jpeg_scaled(input, shrink, limits)
+    {
+        return Ok(DecodedImage {
+            image,
+            format: source_format,
+            color_profile: icc.as_deref().and_then(color::profile_from_icc),
+        });
+    }
 
-    let img = reader
+    let decode_err = |e: image::ImageError| match e {
+        image::ImageError::Limits(e) => TransformError::SourceTooLarge(e.to_string()),
+        e => TransformError::ProcessingFailed(format!("decode failed: {e}")),
+    };
+    let mut decoder = reader.into_decoder().map_err(decode_err)?;
+    // into_decoder は出力バッファの大きさを検証しないため、ImageReader::decode と同様に予約する
+    image_limits
+        .reserve(decoder.total_bytes())
+        .map_err(decode_err)?;
+    let icc = decoder.icc_profile().ok().flatten();
+    let image = DynamicImage::from_decoder(decoder).map_err(decode_err)?;
+
+    Ok(DecodedImage {
+        image,
+        format: source_format,
+        color_profile: icc.as_deref().and_then(color::profile_from_icc),
+    })
+}
+
+fn reject_page_param(page: u32) -> Result<(), TransformError> {
+    if page > 0 {
+        return Err(TransformError::InvalidParams(
+            "page is only supported for multi-page TIFF".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/// デコード前に、向き補正・回転後の原本に対するリサイズ計画を立てる。
+///
+/// 縮小デコード・サムネイル利用の判定に使う。切り取り・任意角度の回転はピクセル座標や
+/// 必要な解像度が変わるため対象外（None）とする。最近傍フィルタもピクセルを混ぜずに
+/// 縮小するための指定のため、DCT スケーリングやサムネイルで先に混ぜないよう対象外とする。
+fn plan_before_decode(
+    params: &TransformParams,
+    header: &SourceHeader,
+    orientation: u32,
+) -> Option<ResizePlan> {
+    if !params.needs_resize()
+        || params.crop.is_some()
+        || params.rotation.rem_euclid(90.0) != 0.0
+        || params.filter == Some(ResizeFilter::Nearest)
+    {
+        return None;
+    }
+    Some(plan_for_header(params, header, orientation))
+}
+
+/// ヘッダの解像度に向き補正・90° の倍数の回転を反映し、リサイズ計画を立てる。
+fn plan_for_header(
+    params: &TransformParams,
+    header: &SourceHeader,
+    orientation: u32,
+) -> ResizePlan {
+    let quarter_turns = (params.rotation / 90.0).rem_euclid(4.0) as u32;
+    let swapped = (orientation >= 5) != (quarter_turns % 2 == 1);
+    let (width, height) = if swapped {
+        (header.height, header.width)
+    } else {
+        (header.width, header.height)
+    };
+    plan_resize(width, height, &params.resize_target())
+}
+
+/// JPEG の shrink-on-load の縮小率（1, 2, 4, 8）を決める。
+///
+/// 縮小後も出力の 2 倍以上の解像度を残し、仕上げのリサイズで画質を保つ。
+fn jpeg_shrink_factor(plan: &ResizePlan) -> u32 {
+    let ratio =
+        (plan.source.width / plan.width as f64).min(plan.source.height / plan.height as f64);
+    [8, 4, 2]
+        .into_iter()
+        .find(|&factor| ratio >= (2 * factor) as f64)
+        .unwrap_or(1)
+}
+
+/// EXIF IFD1 に埋め込まれたサムネイル（カメラの JPEG で 160px 程度）を取り出す。
+///
+/// 出力に必要な解像度を満たし、原本と縦横比が一致する（黒帯付きでない）場合のみ使う。
+/// サムネイルは原本と同じ向きで保存されているため、EXIF Orientation は通常どおり適用する。
+fn decode_exif_thumbnail(
+    input: &[u8],
+    header: &SourceHeader,
+    plan: &ResizePlan,
+) -> Option<DynamicImage> {
+    let exif = exif::Reader::new()
+        .read_from_container(&mut Cursor::new(input))
+        .ok()?;
+    let uint_field = |tag| {
+        exif.get_field(tag, In::THUMBNAIL)
+            .and_then(|f| f.value.get_uint(0))
+            .map(|v| v as usize)
+    };
+    let offset = uint_field(Tag::JPEGInterchangeFormat)?;
+    let length = uint_field(Tag::JPEGInterchangeFormatLength)?;
+    let data = exif.buf().get(offset..offset.checked_add(length)?)?;
+
+    let (thumb_w, thumb_h) = ImageReader::with_format(Cursor::new(data), ImageFormat::Jpeg)
+        .into_dimensions()
+        .ok()?;
+    let (src_w, src_h) = (header.width as u64, header.height as u64);
+
+    // 縦横比は 1px の丸め誤差まで許容する
+    let aspect_error = (thumb_w as u64 * src_h).abs_diff(thumb_h as u64 * src_w);
+    if thumb_w == 0 || thumb_h == 0 || aspect_error > src_w.max(src_h) {
+        return None;
+    }
+
+    // 拡大が必要になるサムネイルは使わない
+    let scale = thumb_w as f64 / header.width as f64;
+    if plan.source.width * scale < plan.width as f64
+        || plan.source.height * scale < plan.height as f64
+    {
+        return None;
+    }
+
+    image::load_from_memory_with_format(data, ImageFormat::Jpeg).ok()
+}
+
+/// JPEG の原本が sRGB 以外の ICC プロファイルを持つか（ヘッダだけを読む）。
+fn jpeg_has_non_srgb_profile(input: &[u8]) -> bool {
+    let mut decoder = JpegDecoder::new(Cursor::new(input));
+    if decoder.read_info().is_err() {
+        return false;
+    }
+    decoder
+        .icc_profile()
+        .is_some_and(|icc| !color::is_srgb_icc(&icc))
+}
+
+/// jpeg-decoder の DCT スケーリングで JPEG を 1/`shrink` に縮小してデコードする。
+///
+/// image クレートの JPEG デコーダ（zune-jpeg）はスケーリングに対応していないため使い分ける。
+/// CMYK・16bit グレースケールやデコードに失敗した場合は None（通常のデコードに任せる）。
+fn decode_jpeg_scaled(
+    input: &[u8],
+    shrink: u32,
+    limits: &DecodeLimits,
+) -> Option<(DynamicImage, Option<Vec<u8>>)> {
+    let mut decoder = JpegDecoder::new(Cursor::new(input));
+    decoder.set_max_decoding_buffer_size(
+        usize::try_from(limits.max_alloc_bytes).unwrap_or(usize::MAX),
+    );
+    decoder.read_info().ok()?;
+    let info = decoder.info()?;
+    if !matches!(
+        info.pixel_format,
+        JpegPixelFormat::RGB24 | JpegPixelFormat::L8
+    ) {
+        return None;
+    }
+
+    let shrink = shrink as u16;
+    let (width, height) = decoder
+        .scale(info.width.div_ceil(shrink), info.height.div_ceil(shrink))
+        .ok()?;
+    let pixels = match decoder.decode() {
+        Ok(pixels) => pixels,
+        Err(e) => {
+            tracing::warn!(error = %e, "scaled JPEG decode failed, falling back to full decode");
+            return None;
+        }
+    };
+
+    let (width, height) = (width as u32, height as u32);
+    let image = match info.pixel_format {
+        JpegPixelFormat::RGB24 => {
+            RgbImage::from_raw(width, height, pixels).map(DynamicImage::ImageRgb8)
+        }
+        _ => GrayImage::from_raw(width, height, pixels).map(DynamicImage::ImageLuma8),
+    }?;
+    Some((image, decoder.icc_profile()))
+}
+
+/// マルチページ TIFF の指定ページをデコードする。ページに ICC プロファイルがあれば併せて返す。
+fn decode_tiff_page(
+    input: &[u8],
+    page: u32,
+    limits: &DecodeLimits,
+) -> Result<(DynamicImage, Option<SourceProfile>), TransformError> {
+    let tiff_err = |e: tiff::TiffError| match e {
+        tiff::TiffError::LimitsExceeded => {
+            TransformError::SourceTooLarge(format!("TIFF page {page} exceeds decode limits"))
+        }
+        e => TransformError::ProcessingFailed(format!("TIFF decode failed (page {page}): {e}")),
+    };
+
+    let mut decoder = TiffDecoder::new(Cursor::new(input))
+        .map_err(tiff_err)?
+        .with_limits(limits.tiff_limits());
+    decoder.seek_to_image(page as usize).map_err(|_| {
+        TransformError::InvalidParams(format!("page {page} does not exist in source TIFF"))
+    })?;
+
+    let (width, height) = decoder.dimensions().map_err(tiff_err)?;
+    let color_type = decoder.colortype().map_err(tiff_err)?;
+    let icc = decoder.get_tag_u8_vec(TiffTag::IccProfile).ok();
+    let data = decoder.read_image().map_err(tiff_err)?;
+
+    let img = match (color_type, data) {
+        (TiffColorType::Gray(8), DecodingResult::U8(buf)) => {
+            ImageBuffer::from_raw(width, height, buf).map(DynamicImage::ImageLuma8)
+        }
+        (TiffColorType::Gray(16), DecodingResult::U16(buf)) => {
+            ImageBuffer::from_raw(width, height, buf).map(DynamicImage::ImageLuma16)
+        }
+        (TiffColorType::GrayA(8), DecodingResult::U8(buf)) => {
+            ImageBuffer::from_raw(width, height, buf).map(DynamicImage::ImageLumaA8)
+        }
+        (TiffColorType::GrayA(16), DecodingResult::U16(buf)) => {
+            ImageBuffer::from_raw(width, height, buf).map(DynamicImage::ImageLumaA16)
+        }
+        (TiffColorType::RGB(8), DecodingResult::U8(buf)) => {
+            ImageBuffer::from_raw(width, height, buf).map(DynamicImage::ImageRgb8)
+        }
+        (TiffColorType::RGB(16), DecodingResult::U16(buf)) => {
+            ImageBuffer::from_raw(width, height, buf).map(DynamicImage::ImageRgb16)
+        }
+        (TiffColorType::RGBA(8), DecodingResult::U8(buf)) => {
+            ImageBuffer::from_raw(width, height, buf).map(DynamicImage::ImageRgba8)
+        }
+        (TiffColorType::RGBA(16), DecodingResult::U16(buf)) => {
+            ImageBuffer::from_raw(width, height, buf).map(DynamicImage::ImageRgba16)
+        }
+        (color_type, _) => {
+            return Err(TransformError::ProcessingFailed(format!(
+                "unsupported TIFF color type on page {page}: {color_type:?}"
+            )));
+        }
+    };
+
+    let img = img.ok_or_else(|| {
+        TransformError::ProcessingFailed(format!("TIFF page {page} has invalid buffer size"))
+    })?;
+    Ok((img, icc.as_deref().and_then(color::profile_from_icc)))
+}
+
+/// ftyp ボックスのブランドから HEIF コンテナ（HEIF/HEIC・AVIF）のフォーマットを判定する。
+///
+/// AVIF も HEIF と同じ ISOBMFF コンテナで mif1 を互換ブランドに含むため、
+/// HEVC 系ブランドより avif/avis のメジャーブランドを優先して判定する。
+fn detect_heif_container(data: &[u8]) -> Option<SourceFormat> {
+    if data.len() < 16 || &data[4..8] != b"ftyp" {
+        return None;
+    }
+
+    // ftyp: size(4) + type(4) + major_brand(4) + minor_version(4) + compatible_brands(4*n)
+    let box_size = u32::from_be_bytes([data[0], data[1], data[2], data[3]]) as usize;
+    let box_end = box_size.clamp(16, data.len());
+    let major_brand = &data[8..12];
+    let brands = || std::iter::once(major_brand).chain(data[16..box_end].chunks_exact(4));
+    let has_brand = |set: &[&[u8; 4]]| brands().any(|brand| set.iter().any(|b| b == &brand));
+
+    if AVIF_BRANDS.iter().any(|b| b == &major_brand) {
+        Some(SourceFormat::Image(ImageFormat::Avif))
+    } else if has_brand(&HEVC_BRANDS) {
+        Some(SourceFormat::Heif)
+    } else if has_brand(&AVIF_BRANDS) {
+        Some(SourceFormat::Image(ImageFormat::Avif))
+    } else if has_brand(&GENERIC_HEIF_BRANDS) {
+        Some(SourceFormat::Heif)
+    } else {
+        None
+    }
+}
+
+/// HEIF コンテナ（HEIF/HEIC・AVIF）をデコードする。
+///
+/// プライマリアイテムの選択（複数画像を含むファイル）と irot/imir（回転・反転）の適用は
+/// heic クレートが行うため、返される画像は表示向きに補正済み。
+/// 10/12bit の画像は 8bit に変換され、アルファは補助画像から合成される。
+/// 色空間は colr ボックスの ICC プロファイル、なければ nclx の色域から求める。
+fn decode_heif(
+    input: &[u8],
+    limits: &DecodeLimits,
+) -> Result<(DynamicImage, Option<SourceProfile>), TransformError> {
+    let info = heic::ImageInfo::from_bytes(input).map_err(|e| {
+        TransformError::ProcessingFailed(format!("failed to read HEIF/AVIF header: {e}"))
+    })?;
+
+    // アルファを持たない画像は RGB で受け取り、不要なチャンネル分のメモリを確保しない
+    let layout = if info.has_alpha {
+        PixelLayout::Rgba8
+    } else {
+        PixelLayout::Rgb8
+    };
+
+    let heic_limits = limits.heic_limits();
+    let output = heic::DecoderConfig::new()
+        .decode_request(input)
+        .with_output_layout(layout)
+        .with_limits(&heic_limits)
         .decode()
-        .map_err(|e| TransformError::ProcessingFailed(format!("decode failed: {e}")))?;
+        .map_err(|e| match e.error() {
+            heic::HeicError::LimitExceeded(msg) => TransformError::SourceTooLarge(msg.to_string()),
+            _ => TransformError::ProcessingFailed(format!("HEIF/AVIF decode failed: {e}")),
+        })?;
+
+    let img = match output.layout {
+        PixelLayout::Rgba8 => RgbaImage::from_raw(output.width, output.height, output.data)
+            .map(DynamicImage::ImageRgba8),
+        _ => RgbImage::from_raw(output.width, output.height, output.data)
+            .map(DynamicImage::ImageRgb8),
+    };
+
+    let img = img.ok_or_else(|| {
+        TransformError::ProcessingFailed(
+            "HEIF/AVIF decoder returned invalid buffer size".to_string(),
+        )
+    })?;
 
-    Ok((img, source_format))
+    let color_profile = match &info.icc_profile {
+        Some(icc) => color::profile_from_icc(icc),
+        None => color::profile_from_cicp(info.color_primaries, info.transfer_characteristics),
+    };
+    Ok((img, color_profile))
 }
 
 /// EXIF から Orientation タグを読み取る（1〜8、失敗時は 1 = 変換なし）。
@@ -151,14 +922,19 @@ fn apply_orientation(img: DynamicImage, orientation: u32) -> DynamicImage {
     }
 }
 
-/// ソース画像の総ピクセル数を検証し、メモリ枯渇を防ぐ。
-fn validate_source_dimensions(width: u32, height: u32) -> Result<(), TransformError> {
-    let total_pixels = width as u64 * height as u64;
-    if total_pixels > MAX_PIXELS {
-        return Err(TransformError::ResolutionTooLarge { width, height });
-    }
+/// 向き補正後の画像から `crop` の矩形を切り出す。
+fn crop_image(img: DynamicImage, crop: &CropRect) -> Result<DynamicImage, TransformError> {
+    let (src_w, src_h) = (img.width(), img.height());
+    let (x, y, w, h) = crop.resolve(src_w, src_h).ok_or_else(|| {
+        TransformError::InvalidParams(format!(
+            "crop rectangle is empty or exceeds source image bounds ({src_w}x{src_h})"
+        ))
+    })?;
 
-    Ok(())
+    if (x, y, w, h) == (0, 0, src_w, src_h) {
+        return Ok(img);
+    }
+    Ok(img.crop_imm(x, y, w, h))
 }
 
 /// 出力画像のサイズを検証する。
@@ -171,17 +947,30 @@ fn validate_output_dimensions(width: u32, height: u32) -> Result<(), TransformEr
 }
 
 /// 出力フォーマットを決定する。
+///
+/// ブラウザでそのまま表示できないフォーマットは、指定がなければ次のように変換する。
+/// - GIF / BMP: 可逆で透過も保持できる PNG（アニメーション GIF は先頭フレームのみ）
+/// - HEIF/HEIC / TIFF: 写真が中心のため JPEG（アルファを持つ場合のみ PNG）
 fn determine_output_format(
-    source_format: Option<ImageFormat>,
+    source_format: Option<SourceFormat>,
     requested_format: Option<OutputFormat>,
+    has_alpha: bool,
 ) -> OutputFormat {
+    let photo_output = if has_alpha {
+        OutputFormat::Png
+    } else {
+        OutputFormat::Jpeg
+    };
+
     requested_format.unwrap_or_else(|| {
         source_format
             .and_then(|f| match f {
-                ImageFormat::Jpeg => Some(OutputFormat::Jpeg),
-                ImageFormat::Png => Some(OutputFormat::Png),
-                ImageFormat::WebP => Some(OutputFormat::WebP),
-                ImageFormat::Avif => Some(OutputFormat::Avif),
+                SourceFormat::Image(ImageFormat::Jpeg) => Some(OutputFormat::Jpeg),
+                SourceFormat::Image(ImageFormat::Png) => Some(OutputFormat::Png),
+                SourceFormat::Image(ImageFormat::WebP) => Some(OutputFormat::WebP),
+                SourceFormat::Image(ImageFormat::Avif) => Some(OutputFormat::Avif),
+                SourceFormat::Image(ImageFormat::Gif | ImageFormat::Bmp) => Some(OutputFormat::Png),
+                SourceFormat::Image(ImageFormat::Tiff) | SourceFormat::Heif => Some(photo_output),
                 _ => None,
             })
             .unwrap_or(OutputFormat::Jpeg)
@@ -210,115 +999,794 @@ fn validate_params(params: &TransformParams) -> Result<(), TransformError> {
             "height must be 1-{MAX_DIMENSION}, got {h}"
         )));
     }
+    if params.aspect_ratio.is_some() && params.width.is_some() && params.height.is_some() {
+        return Err(TransformError::InvalidParams(
+            "ar cannot be combined with both w and h".to_string(),
+        ));
+    }
+    if !params.rotation.is_finite() {
+        return Err(TransformError::InvalidParams(format!(
+            "rot must be a finite number of degrees, got {}",
+            params.rotation
+        )));
+    }
+    if let Gravity::Focal { x, y } = params.gravity
+        && !((0.0..=1.0).contains(&x) && (0.0..=1.0).contains(&y))
+    {
+        return Err(TransformError::InvalidParams(format!(
+            "fx and fy must be 0.0-1.0, got {x}, {y}"
+        )));
+    }
+    if !AVIF_SPEED_RANGE.contains(&params.avif_speed) {
+        return Err(TransformError::InvalidParams(format!(
+            "speed must be 1-10, got {}",
+            params.avif_speed
+        )));
+    }
+    if !(0.0..=MAX_SHARPEN).contains(&params.sharpen) {
+        return Err(TransformError::InvalidParams(format!(
+            "sharpen must be 0-{MAX_SHARPEN}, got {}",
+            params.sharpen
+        )));
+    }
     Ok(())
 }
 
-/// "contain" モードで出力サイズを計算する（withoutEnlargement）。
+/// `filter` の補間フィルタを使用して fast_image_resize で DynamicImage をリサイズする。
 ///
-/// - w のみ: 幅に合わせて拡縮、高さは自動（元画像より大きい場合は拡大しない）
-/// - h のみ: 高さに合わせて拡縮、幅は自動（元画像より大きい場合は拡大しない）
-/// - 両方: バウンディングボックス内に収める（元画像より大きい場合は拡大しない）
-/// - どちらもなし: 元のサイズを維持
-fn calculate_contain_dimensions(
-    src_w: u32,
-    src_h: u32,
-    target_w: Option<u32>,
-    target_h: Option<u32>,
-) -> (u32, u32) {
-    match (target_w, target_h) {
-        (Some(w), Some(h)) => {
-            let scale_w = w as f64 / src_w as f64;
-            let scale_h = h as f64 / src_h as f64;
-            let scale = scale_w.min(scale_h).min(1.0); // withoutEnlargement
-            let new_w = (src_w as f64 * scale).round() as u32;
-            let new_h = (src_h as f64 * scale).round() as u32;
-            (new_w.max(1), new_h.max(1))
-        }
-        (Some(w), None) => {
-            if w >= src_w {
-                return (src_w, src_h); // withoutEnlargement
-            }
-            let scale = w as f64 / src_w as f64;
-            let new_h = (src_h as f64 * scale).round() as u32;
-            (w, new_h.max(1))
-        }
-        (None, Some(h)) => {
-            if h >= src_h {
-                return (src_w, src_h); // withoutEnlargement
-            }
-            let scale = h as f64 / src_h as f64;
-            let new_w = (src_w as f64 * scale).round() as u32;
-            (new_w.max(1), h)
-        }
-        (None, None) => (src_w, src_h),
-    }
-}
-
-/// Lanczos3 フィルタを使用して fast_image_resize で DynamicImage をリサイズする。
+/// `source` の領域だけを切り出してリサイズする（全体を使う場合は `Region::full`）。
+/// ピクセル形式（グレースケール・RGB・16bit 等）は変換せずそのままリサイズする。
+/// `linear_light` の場合は sRGB のガンマを外した線形の値で補間し、縮小時に細部が暗くなるのを防ぐ。
 fn resize_image(
     img: &DynamicImage,
+    source: Region,
     dst_w: u32,
     dst_h: u32,
+    filter: ResizeFilter,
+    linear_light: bool,
 ) -> Result<DynamicImage, TransformError> {
-    let src_rgba = img.to_rgba8();
-    let (src_w, src_h) = (src_rgba.width(), src_rgba.height());
-
-    let src_fr =
-        Image::from_vec_u8(src_w, src_h, src_rgba.into_raw(), PixelType::U8x4).map_err(|e| {
-            TransformError::ProcessingFailed(format!("failed to create source image: {e}"))
-        })?;
+    // fast_image_resize が扱えない形式（image クレートの将来の拡張）のみ RGBA8 に変換する
+    let Some(pixel_type) = img.pixel_type() else {
+        return resize_image(
+            &DynamicImage::ImageRgba8(img.to_rgba8()),
+            source,
+            dst_w,
+            dst_h,
+            filter,
+            linear_light,
+        );
+    };
 
-    let mut dst_fr = Image::new(dst_w, dst_h, PixelType::U8x4);
+    let mut dst = DynamicImage::new(dst_w, dst_h, img.color());
 
     let mut resizer = Resizer::new();
-    let options = ResizeOptions::new().resize_alg(ResizeAlg::Convolution(
-        fast_image_resize::FilterType::Lanczos3,
-    ));
-    resizer
-        .resize(&src_fr, &mut dst_fr, Some(&options))
-        .map_err(|e| TransformError::ProcessingFailed(format!("resize failed: {e}")))?;
-
-    let result_buf =
-        image::RgbaImage::from_raw(dst_w, dst_h, dst_fr.into_vec()).ok_or_else(|| {
-            TransformError::ProcessingFailed("failed to create output image buffer".to_string())
-        })?;
+    let options = ResizeOptions::new().resize_alg(filter.resize_alg()).crop(
+        source.left,
+        source.top,
+        source.width,
+        source.height,
+    );
+    let resize_err = |e: fast_image_resize::ResizeError| {
+        TransformError::ProcessingFailed(format!("resize failed: {e}"))
+    };
+
+    match linear_pixel_type(pixel_type).filter(|_| linear_light) {
+        Some(linear_type) => {
+            let map_err = |e: fast_image_resize::MappingError| {
+                TransformError::ProcessingFailed(format!("linear light conversion failed: {e}"))
+            };
+            let mapper = srgb_mapper();
+
+            let mut linear_src = Image::new(img.width(), img.height(), linear_type);
+            mapper.forward_map(img, &mut linear_src).map_err(map_err)?;
+            let mut linear_dst = Image::new(dst_w, dst_h, linear_type);
+            resizer
+                .resize(&linear_src, &mut linear_dst, Some(&options))
+                .map_err(resize_err)?;
+            mapper
+                .backward_map(&linear_dst, &mut dst)
+                .map_err(map_err)?;
+        }
+        None => resizer
+            .resize(img, &mut dst, Some(&options))
+            .map_err(resize_err)?,
+    }
+
+    Ok(dst)
+}
+
+/// 線形の値を保持するピクセル形式。8bit のままでは暗部の階調が潰れるため 16bit にする。
+///
+/// 浮動小数点の画像は対象外（そのままリサイズする）。
+fn linear_pixel_type(pixel_type: PixelType) -> Option<PixelType> {
+    match pixel_type {
+        PixelType::U8 | PixelType::U16 => Some(PixelType::U16),
+        PixelType::U8x2 | PixelType::U16x2 => Some(PixelType::U16x2),
+        PixelType::U8x3 | PixelType::U16x3 => Some(PixelType::U16x3),
+        PixelType::U8x4 | PixelType::U16x4 => Some(PixelType::U16x4),
+        _ => None,
+    }
+}
 
-    Ok(DynamicImage::ImageRgba8(result_buf))
+/// sRGB ⇔ 線形の変換テーブル（作成に時間がかかるため使い回す）。
+fn srgb_mapper() -> &'static PixelComponentMapper {
+    static MAPPER: OnceLock<PixelComponentMapper> = OnceLock::new();
+    MAPPER.get_or_init(create_srgb_mapper)
+}
+
+/// `ffffff` / `ffffff80` 形式（`#` は省略可）の 16 進カラーをパースする。
+pub fn parse_hex_color(s: &str) -> Option<Rgba<u8>> {
+    let hex = s.strip_prefix('#').unwrap_or(s);
+    if !matches!(hex.len(), 6 | 8) || !hex.is_ascii() {
+        return None;
+    }
+    let channel = |i: usize| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok();
+    let alpha = if hex.len() == 8 { channel(6)? } else { 255 };
+    Some(Rgba([channel(0)?, channel(2)?, channel(4)?, alpha]))
+}
+
+/// 余白色のデフォルト。JPEG は透過できないため白、それ以外は透明にする。
+fn pad_color(format: OutputFormat) -> Rgba<u8> {
+    match format {
+        OutputFormat::Jpeg => Rgba([255, 255, 255, 255]),
+        _ => Rgba([0, 0, 0, 0]),
+    }
+}
+
+/// リサイズ後の画像をキャンバスの指定位置に配置し、余白を `color` で埋める。
+fn pad_image(img: &DynamicImage, canvas: Canvas, color: Rgba<u8>) -> DynamicImage {
+    let mut padded = RgbaImage::from_pixel(canvas.width, canvas.height, color);
+    image::imageops::overlay(
+        &mut padded,
+        &img.to_rgba8(),
+        canvas.x as i64,
+        canvas.y as i64,
+    );
+    DynamicImage::ImageRgba8(padded)
+}
+
+/// libwebp で lossy WebP にエンコードする。アルファを持つ画像は RGBA のまま渡して透過を保持する。
+fn encode_webp_lossy(img: &DynamicImage, quality: u8) -> Result<Vec<u8>, TransformError> {
+    let (width, height) = (img.width(), img.height());
+    let result = match img {
+        DynamicImage::ImageRgba8(rgba) => {
+            webp::Encoder::from_rgba(rgba, width, height).encode_simple(false, quality as f32)
+        }
+        DynamicImage::ImageRgb8(rgb) => {
+            webp::Encoder::from_rgb(rgb, width, height).encode_simple(false, quality as f32)
+        }
+        _ if img.color().has_alpha() => {
+            let rgba = img.to_rgba8();
+            webp::Encoder::from_rgba(&rgba, width, height).encode_simple(false, quality as f32)
+        }
+        _ => {
+            let rgb = img.to_rgb8();
+            webp::Encoder::from_rgb(&rgb, width, height).encode_simple(false, quality as f32)
+        }
+    };
+
+    result
+        .map(|memory| memory.to_vec())
+        .map_err(|e| TransformError::ProcessingFailed(format!("WebP encode failed: {e:?}")))
 }
 
 /// 指定されたフォーマットと品質で DynamicImage をエンコードする。
+///
+/// `lossless` は WebP に、`avif_speed` は AVIF にのみ適用される。
+/// `color` の ICC プロファイルを JPEG・PNG・WebP に埋め込む。
+/// ピクセル形式はエンコーダが受け付けない場合のみ変換する。
+/// AVIF は ravif が nclx で BT.709/sRGB を記録するため、Display P3 の場合は色域を書き換える。
 fn encode_image(
     img: &DynamicImage,
     format: OutputFormat,
     quality: u8,
+    lossless: bool,
+    avif_speed: u8,
+    color: &OutputColor,
 ) -> Result<Vec<u8>, TransformError> {
+    let img = &*encodable_pixels(img, format, lossless);
     let mut buf = Cursor::new(Vec::new());
+    // 出力のプロファイルは RGB 用のため、グレースケールの画像には付けない（PNG では仕様違反になる）
+    let icc = color
+        .icc()
+        .filter(|_| img.color().has_color())
+        .map(<[u8]>::to_vec);
+    let icc_err = |e: image::error::UnsupportedError| {
+        TransformError::ProcessingFailed(format!("failed to embed ICC profile: {e}"))
+    };
 
     match format {
         OutputFormat::Jpeg => {
-            let encoder = JpegEncoder::new_with_quality(&mut buf, quality);
-            img.to_rgb8().write_with_encoder(encoder).map_err(|e| {
+            let mut encoder = JpegEncoder::new_with_quality(&mut buf, quality);
+            if let Some(icc) = icc {
+                encoder.set_icc_profile(icc).map_err(icc_err)?;
+            }
+            img.write_with_encoder(encoder).map_err(|e| {
                 TransformError::ProcessingFailed(format!("JPEG encode failed: {e}"))
             })?;
         }
         OutputFormat::Png => {
-            img.write_to(&mut buf, ImageFormat::Png)
+            let mut encoder = PngEncoder::new(&mut buf);
+            if let Some(icc) = icc {
+                encoder.set_icc_profile(icc).map_err(icc_err)?;
+            }
+            img.write_with_encoder(encoder)
                 .map_err(|e| TransformError::ProcessingFailed(format!("PNG encode failed: {e}")))?;
+            if *color == OutputColor::Srgb {
+                return insert_png_srgb_chunk(buf.into_inner());
+            }
         }
-        OutputFormat::WebP => {
-            // image クレートの WebP エンコーダはロスレスのみ対応（quality は無視）
-            let encoder = WebPEncoder::new_lossless(&mut buf);
+        OutputFormat::WebP if lossless => {
+            let mut encoder = WebPEncoder::new_lossless(&mut buf);
+            if let Some(icc) = icc {
+                encoder.set_icc_profile(icc).map_err(icc_err)?;
+            }
             img.write_with_encoder(encoder).map_err(|e| {
                 TransformError::ProcessingFailed(format!("WebP encode failed: {e}"))
             })?;
         }
+        OutputFormat::WebP => {
+            // image クレートの WebP エンコーダはロスレスのみ対応のため、lossy は libwebp を使う
+            let webp = encode_webp_lossy(img, quality)?;
+            return match icc {
+                Some(icc) => embed_webp_icc(
+                    webp,
+                    &icc,
+                    img.width(),
+                    img.height(),
+                    img.color().has_alpha(),
+                ),
+                None => Ok(webp),
+            };
+        }
         OutputFormat::Avif => {
-            let encoder = AvifEncoder::new_with_speed_quality(&mut buf, 4, quality);
+            // スレッド数は指定せず、TRANSFORM_THREADS で設定した共有スレッドプールで
+            // rav1e のタイルを並列にエンコードする
+            let encoder = AvifEncoder::new_with_speed_quality(&mut buf, avif_speed, quality);
             img.write_with_encoder(encoder).map_err(|e| {
                 TransformError::ProcessingFailed(format!("AVIF encode failed: {e}"))
             })?;
+            if *color == OutputColor::DisplayP3 {
+                return avif::set_color_primaries(buf.into_inner(), avif::DISPLAY_P3_PRIMARIES)
+                    .ok_or_else(|| {
+                        TransformError::ProcessingFailed(
+                            "failed to tag AVIF output as Display P3".to_string(),
+                        )
+                    });
+            }
         }
     }
 
     Ok(buf.into_inner())
 }
+
+/// 出力フォーマットのエンコーダが受け付けるピクセル形式に変換する。
+///
+/// 受け付ける形式はそのまま借用し、変換が必要な場合だけコピーを作る。
+/// JPEG はアルファを持てないため捨てる（余白・背景は変換前に不透明色で塗られている）。
+fn encodable_pixels(
+    img: &DynamicImage,
+    format: OutputFormat,
+    lossless: bool,
+) -> Cow<'_, DynamicImage> {
+    use DynamicImage::{ImageLuma8, ImageLumaA8, ImageRgb8, ImageRgb32F, ImageRgba8, ImageRgba32F};
+
+    let supported = match format {
+        OutputFormat::Jpeg => matches!(img, ImageLuma8(_) | ImageRgb8(_)),
+        OutputFormat::Png => !matches!(img, ImageRgb32F(_) | ImageRgba32F(_)),
+        OutputFormat::WebP if lossless => {
+            matches!(
+                img,
+                ImageLuma8(_) | ImageLumaA8(_) | ImageRgb8(_) | ImageRgba8(_)
+            )
+        }
+        // lossy WebP（libwebp）と AVIF（ravif）は 8bit の RGB / RGBA のみ
+        OutputFormat::WebP | OutputFormat::Avif => matches!(img, ImageRgb8(_) | ImageRgba8(_)),
+    };
+    if supported {
+        return Cow::Borrowed(img);
+    }
+
+    let color = img.color();
+    let converted = match format {
+        OutputFormat::Jpeg if !color.has_color() => ImageLuma8(img.to_luma8()),
+        OutputFormat::Jpeg => ImageRgb8(img.to_rgb8()),
+        OutputFormat::Png if color.has_alpha() => DynamicImage::ImageRgba16(img.to_rgba16()),
+        OutputFormat::Png => DynamicImage::ImageRgb16(img.to_rgb16()),
+        _ if color.has_alpha() => ImageRgba8(img.to_rgba8()),
+        _ => ImageRgb8(img.to_rgb8()),
+    };
+    Cow::Owned(converted)
+}
+
+/// PNG の IHDR の直後に sRGB チャンク（レンダリングインテントは知覚的）を追加する。
+///
+/// ICC プロファイル（約 600 バイト）の代わりに 13 バイトで sRGB であることを示す。
+fn insert_png_srgb_chunk(png: Vec<u8>) -> Result<Vec<u8>, TransformError> {
+    const IHDR_END: usize = 8 + 8 + 13 + 4; // シグネチャ + IHDR（長さ・種別・データ・CRC）
+
+    if png.get(12..16) != Some(b"IHDR".as_slice()) || png.len() < IHDR_END {
+        return Err(TransformError::ProcessingFailed(
+            "PNG encoder returned an invalid header".to_string(),
+        ));
+    }
+
+    let chunk = *b"sRGB\0";
+    let mut out = Vec::with_capacity(png.len() + 4 + chunk.len() + 4);
+    out.extend_from_slice(&png[..IHDR_END]);
+    out.extend_from_slice(&1u32.to_be_bytes());
+    out.extend_from_slice(&chunk);
+    out.extend_from_slice(&crc32(&chunk).to_be_bytes());
+    out.extend_from_slice(&png[IHDR_END..]);
+    Ok(out)
+}
+
+/// PNG のチャンクの CRC-32（ISO 3309）。
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = u32::MAX;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+/// libwebp の出力（simple / extended フォーマット）に ICCP チャンクを追加する。
+///
+/// ICCP は VP8X の直後に置く必要があるため、simple フォーマット（VP8 / VP8L のみ）の場合は
+/// VP8X チャンクを新たに作って extended フォーマットに変換する。
+fn embed_webp_icc(
+    webp: Vec<u8>,
+    icc: &[u8],
+    width: u32,
+    height: u32,
+    has_alpha: bool,
+) -> Result<Vec<u8>, TransformError> {
+    const VP8X_FLAG_ICC: u8 = 0x20;
+    const VP8X_FLAG_ALPHA: u8 = 0x10;
+    const HEADER_LEN: usize = 12; // "RIFF" + size + "WEBP"
+    const VP8X_CHUNK_LEN: usize = 8 + 10;
+
+    if webp.len() < HEADER_LEN + 8 || &webp[0..4] != b"RIFF" || &webp[8..12] != b"WEBP" {
+        return Err(TransformError::ProcessingFailed(
+            "libwebp returned an invalid RIFF container".to_string(),
+        ));
+    }
+
+    let mut out = Vec::with_capacity(webp.len() + VP8X_CHUNK_LEN + 8 + icc.len() + 1);
+    out.extend_from_slice(&webp[..HEADER_LEN]);
+
+    let body = &webp[HEADER_LEN..];
+    let rest = if &body[0..4] == b"VP8X" {
+        let Some(vp8x) = body.get(..VP8X_CHUNK_LEN) else {
+            return Err(TransformError::ProcessingFailed(
+                "libwebp returned a truncated VP8X chunk".to_string(),
+            ));
+        };
+        let mut vp8x = vp8x.to_vec();
+        vp8x[8] |= VP8X_FLAG_ICC;
+        out.extend_from_slice(&vp8x);
+        &body[VP8X_CHUNK_LEN..]
+    } else {
+        let mut flags = VP8X_FLAG_ICC;
+        if has_alpha {
+            flags |= VP8X_FLAG_ALPHA;
+        }
+        out.extend_from_slice(b"VP8X");
+        out.extend_from_slice(&10u32.to_le_bytes());
+        out.extend_from_slice(&[flags, 0, 0, 0]);
+        out.extend_from_slice(&(width - 1).to_le_bytes()[..3]);
+        out.extend_from_slice(&(height - 1).to_le_bytes()[..3]);
+        body
+    };
+
+    out.extend_from_slice(b"ICCP");
+    out.extend_from_slice(&(icc.len() as u32).to_le_bytes());
+    out.extend_from_slice(icc);
+    if icc.len() % 2 == 1 {
+        out.push(0); // チャンクは偶数長にパディングする
+    }
+    out.extend_from_slice(rest);
+
+    let riff_size = (out.len() - 8) as u32;
+    out[4..8].copy_from_slice(&riff_size.to_le_bytes());
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn params() -> TransformParams {
+        TransformParams {
+            width: Some(400),
+            height: None,
+            format: None,
+            quality: None,
+            page: None,
+            lossless: false,
+            fit: None,
+            gravity: Gravity::default(),
+            aspect_ratio: None,
+            crop: None,
+            rotation: 0.0,
+            flip: None,
+            background: None,
+            color_space: ColorSpace::default(),
+            linear_light: false,
+            filter: None,
+            sharpen: 0.0,
+            enlarge: false,
+            avif_speed: DEFAULT_AVIF_SPEED,
+        }
+    }
+
+    #[test]
+    fn cache_key_is_versioned_and_explicit() {
+        assert_eq!(
+            params().cache_key("photos/a.jpg"),
+            "v1|photos/a.jpg|w=400|h=-|f=-|q=80|page=0|lossless=false|fit=contain|\
+             gravity=center|ar=-|crop=-|rot=0|flip=-|bg=-|cs=srgb|linear=false|filter=-|\
+             sharpen=0|enlarge=false|speed=4"
+        );
+    }
+
+    #[test]
+    fn cache_key_is_shared_by_equivalent_params() {
+        let base = params().cache_key("a.jpg");
+        let equivalent = [
+            TransformParams {
+                rotation: 360.0,
+                ..params()
+            },
+            TransformParams {
+                rotation: -0.0,
+                ..params()
+            },
+            TransformParams {
+                fit: Some(Fit::Inside),
+                ..params()
+            },
+            TransformParams {
+                quality: Some(DEFAULT_QUALITY),
+                ..params()
+            },
+            TransformParams {
+                page: Some(0),
+                ..params()
+            },
+        ];
+        for p in equivalent {
+            assert_eq!(p.cache_key("a.jpg"), base, "{p:?}");
+        }
+
+        let rotated = TransformParams {
+            rotation: -90.0,
+            ..params()
+        };
+        assert_eq!(
+            rotated.cache_key("a.jpg"),
+            TransformParams {
+                rotation: 270.0,
+                ..params()
+            }
+            .cache_key("a.jpg")
+        );
+    }
+
+    #[test]
+    fn cache_key_distinguishes_different_outputs() {
+        let base = params().cache_key("a.jpg");
+        let different = [
+            TransformParams {
+                width: Some(401),
+                ..params()
+            },
+            TransformParams {
+                format: Some(OutputFormat::WebP),
+                ..params()
+            },
+            TransformParams {
+                rotation: 90.0,
+                ..params()
+            },
+            TransformParams {
+                gravity: Gravity::Focal { x: 0.5, y: 0.5 },
+                ..params()
+            },
+            TransformParams {
+                crop: CropRect::parse("0,0,50%,50%"),
+                ..params()
+            },
+            TransformParams {
+                background: Some(Rgba([255, 255, 255, 255])),
+                ..params()
+            },
+        ];
+        for p in different {
+            assert_ne!(p.cache_key("a.jpg"), base, "{p:?}");
+        }
+        assert_ne!(params().cache_key("b.jpg"), base);
+    }
+
+    /// RIFF コンテナのヘッダーを確認し、チャンクを (FourCC, 内容) の順に取り出す。
+    fn webp_chunks(webp: &[u8]) -> Vec<([u8; 4], &[u8])> {
+        assert_eq!(&webp[0..4], b"RIFF");
+        assert_eq!(&webp[8..12], b"WEBP");
+        let riff_size = u32::from_le_bytes(webp[4..8].try_into().unwrap()) as usize;
+        assert_eq!(riff_size, webp.len() - 8);
+
+        let mut chunks = Vec::new();
+        let mut body = &webp[12..];
+        while !body.is_empty() {
+            let fourcc = body[0..4].try_into().unwrap();
+            let len = u32::from_le_bytes(body[4..8].try_into().unwrap()) as usize;
+            chunks.push((fourcc, &body[8..8 + len]));
+            body = &body[(8 + len + len % 2).min(body.len())..];
+        }
+        chunks
+    }
+
+    fn assert_embedded_icc(webp: &[u8], icc: &[u8], expected: &[&[u8; 4]], alpha: bool) {
+        let chunks = webp_chunks(webp);
+        let fourccs: Vec<_> = chunks.iter().map(|(fourcc, _)| fourcc).collect();
+        assert_eq!(fourccs, expected);
+
+        let vp8x = chunks[0].1;
+        assert_eq!(vp8x[0] & 0x20, 0x20, "ICC flag");
+        assert_eq!(vp8x[0] & 0x10 != 0, alpha, "alpha flag");
+        assert_eq!(&vp8x[4..7], &[4, 0, 0], "canvas width - 1");
+        assert_eq!(&vp8x[7..10], &[2, 0, 0], "canvas height - 1");
+        assert_eq!(chunks[1].1, icc);
+
+        let mut decoder = image::codecs::webp::WebPDecoder::new(Cursor::new(webp)).unwrap();
+        assert_eq!(decoder.icc_profile().unwrap().as_deref(), Some(icc));
+        assert_eq!(decoder.dimensions(), (5, 3));
+        assert_eq!(decoder.color_type().has_alpha(), alpha);
+    }
+
+    #[test]
+    fn embed_webp_icc_converts_simple_format_to_extended() {
+        let img =
+            DynamicImage::ImageRgb8(image::RgbImage::from_pixel(5, 3, image::Rgb([200, 10, 10])));
+        let webp = encode_webp_lossy(&img, 80).unwrap();
+        assert_eq!(&webp[12..16], b"VP8 ");
+
+        // 奇数長のプロファイルはパディングされる
+        let icc = b"icc";
+        let out = embed_webp_icc(webp, icc, 5, 3, false).unwrap();
+        assert_embedded_icc(&out, icc, &[b"VP8X", b"ICCP", b"VP8 "], false);
+    }
+
+    #[test]
+    fn embed_webp_icc_keeps_existing_extended_chunks() {
+        let img =
+            DynamicImage::ImageRgba8(image::RgbaImage::from_pixel(5, 3, Rgba([200, 10, 10, 128])));
+        let webp = encode_webp_lossy(&img, 80).unwrap();
+        assert_eq!(&webp[12..16], b"VP8X");
+
+        let icc = b"icc!";
+        let out = embed_webp_icc(webp, icc, 5, 3, true).unwrap();
+        assert_embedded_icc(&out, icc, &[b"VP8X", b"ICCP", b"ALPH", b"VP8 "], true);
+    }
+
+    #[test]
+    fn embed_webp_icc_rejects_truncated_vp8x() {
+        let mut webp = b"RIFF\0\0\0\0WEBPVP8X\x0a\0\0\0\0\0".to_vec();
+        let riff_size = (webp.len() - 8) as u32;
+        webp[4..8].copy_from_slice(&riff_size.to_le_bytes());
+        assert!(matches!(
+            embed_webp_icc(webp, b"icc", 5, 3, false),
+            Err(TransformError::ProcessingFailed(_))
+        ));
+    }
+
+    #[test]
+    fn detects_jpeg_with_non_srgb_profile() {
+        let encode = |icc: Option<&[u8]>| {
+            let img = DynamicImage::ImageRgb8(RgbImage::new(8, 8));
+            let mut buf = Cursor::new(Vec::new());
+            let mut encoder = JpegEncoder::new(&mut buf);
+            if let Some(icc) = icc {
+                encoder.set_icc_profile(icc.to_vec()).unwrap();
+            }
+            img.write_with_encoder(encoder).unwrap();
+            buf.into_inner()
+        };
+
+        assert!(jpeg_has_non_srgb_profile(&encode(Some(
+            OutputColor::DisplayP3.icc().unwrap()
+        ))));
+        assert!(!jpeg_has_non_srgb_profile(&encode(Some(
+            &moxcms::ColorProfile::new_srgb().encode().unwrap()
+        ))));
+        assert!(!jpeg_has_non_srgb_profile(&encode(None)));
+    }
+
+    #[test]
+    fn estimates_memory_from_the_largest_intermediate_buffer() {
+        let encode = |img: DynamicImage| {
+            let mut buf = Cursor::new(Vec::new());
+            img.write_to(&mut buf, ImageFormat::Png).unwrap();
+            Bytes::from(buf.into_inner())
+        };
+        let rgb8 = encode(DynamicImage::ImageRgb8(RgbImage::new(100, 50)));
+        let rgb16 = encode(DynamicImage::ImageRgb16(ImageBuffer::new(100, 50)));
+        let estimate = |input: &Bytes, params: TransformParams| {
+            estimate_memory(input, &params).unwrap() / WORKING_COPIES
+        };
+
+        // 拡大しないため、デコード結果（RGBA 換算）が最大
+        assert_eq!(estimate(&rgb8, params()), 100 * 50 * 4);
+        // 45° の回転は 107 × 107 の RGBA8 / RGBA16 に広がる
+        let rotated = || TransformParams {
+            rotation: 45.0,
+            ..params()
+        };
+        assert_eq!(estimate(&rgb8, rotated()), 107 * 107 * 4);
+        assert_eq!(estimate(&rgb16, rotated()), 107 * 107 * 8);
+        // 回転前に切り取る場合は切り取った領域を回転する
+        let cropped = TransformParams {
+            crop: CropRect::parse("0,0,10,10"),
+            ..rotated()
+        };
+        assert_eq!(estimate(&rgb8, cropped), 100 * 50 * 4);
+        // pad の余白を含むキャンバス、enlarge の出力
+        let padded = TransformParams {
+            height: Some(400),
+            fit: Some(Fit::Pad),
+            ..params()
+        };
+        assert_eq!(estimate(&rgb8, padded), 400 * 400 * 4);
+        let enlarged = TransformParams {
+            enlarge: true,
+            ..params()
+        };
+        assert_eq!(estimate(&rgb8, enlarged), 400 * 200 * 4);
+    }
+
+    #[test]
+    fn embeds_a_profile_only_for_non_srgb_output() {
+        let img = DynamicImage::ImageRgb8(RgbImage::from_pixel(5, 3, image::Rgb([200, 40, 90])));
+        let encode = |format, color: &OutputColor| {
+            encode_image(&img, format, 80, false, DEFAULT_AVIF_SPEED, color).unwrap()
+        };
+        let png_chunks = |png: &[u8]| {
+            let mut chunks = Vec::new();
+            let mut rest = &png[8..];
+            while rest.len() >= 12 {
+                let len = u32::from_be_bytes(rest[..4].try_into().unwrap()) as usize;
+                let data = &rest[8..8 + len];
+                let crc = u32::from_be_bytes(rest[8 + len..12 + len].try_into().unwrap());
+                assert_eq!(crc, crc32(&rest[4..8 + len]));
+                chunks.push((<[u8; 4]>::try_from(&rest[4..8]).unwrap(), data.to_vec()));
+                rest = &rest[12 + len..];
+            }
+            chunks
+        };
+        let jpeg_icc = |jpeg: &[u8]| {
+            let mut decoder = JpegDecoder::new(Cursor::new(jpeg));
+            decoder.read_info().unwrap();
+            decoder.icc_profile()
+        };
+
+        let png = encode(OutputFormat::Png, &OutputColor::Srgb);
+        let chunks = png_chunks(&png);
+        assert_eq!(chunks[1], (*b"sRGB", vec![0]));
+        assert!(chunks.iter().all(|(kind, _)| kind != b"iCCP"));
+        assert_eq!(
+            image::load_from_memory(&png).unwrap().to_rgb8(),
+            img.to_rgb8()
+        );
+        let png = encode(OutputFormat::Png, &OutputColor::DisplayP3);
+        assert!(png_chunks(&png).iter().any(|(kind, _)| kind == b"iCCP"));
+
+        assert_eq!(
+            jpeg_icc(&encode(OutputFormat::Jpeg, &OutputColor::Srgb)),
+            None
+        );
+        assert_eq!(
+            jpeg_icc(&encode(OutputFormat::Jpeg, &OutputColor::DisplayP3)).as_deref(),
+            OutputColor::DisplayP3.icc()
+        );
+
+        let webp = encode(OutputFormat::WebP, &OutputColor::Srgb);
+        assert!(webp_chunks(&webp).iter().all(|(kind, _)| kind != b"ICCP"));
+        let icc = OutputColor::Icc(b"icc".to_vec());
+        let webp = encode(OutputFormat::WebP, &icc);
+        assert_embedded_icc(&webp, b"icc", &[b"VP8X", b"ICCP", b"VP8 "], false);
+    }
+
+    #[test]
+    fn jpeg_shrink_factor_keeps_twice_the_output_resolution() {
+        let factor = |width| {
+            let params = TransformParams {
+                width: Some(width),
+                ..params()
+            };
+            jpeg_shrink_factor(&plan_resize(4000, 4000, &params.resize_target()))
+        };
+        // 縮小率が 2 × factor 倍以上になったところでその factor を選ぶ
+        assert_eq!(factor(4000), 1);
+        assert_eq!(factor(1001), 1);
+        assert_eq!(factor(1000), 2);
+        assert_eq!(factor(501), 2);
+        assert_eq!(factor(500), 4);
+        assert_eq!(factor(251), 4);
+        assert_eq!(factor(250), 8);
+        assert_eq!(factor(40), 8);
+    }
+
+    #[test]
+    fn png_output_keeps_16_bit_depth() {
+        let pixel = image::Rgb([1000u16, 30000, 65000]);
+        let mut input = Cursor::new(Vec::new());
+        DynamicImage::ImageRgb16(ImageBuffer::from_pixel(8, 8, pixel))
+            .write_to(&mut input, ImageFormat::Png)
+            .unwrap();
+        let params = TransformParams {
+            width: Some(4),
+            format: Some(OutputFormat::Png),
+            ..params()
+        };
+
+        let (output, content_type) = transform(
+            &Bytes::from(input.into_inner()),
+            &params,
+            &DecodeLimits::default(),
+        )
+        .unwrap();
+        assert_eq!(content_type, "image/png");
+        let DynamicImage::ImageRgb16(output) = image::load_from_memory(&output).unwrap() else {
+            panic!("expected a 16-bit PNG");
+        };
+        assert_eq!(output.dimensions(), (4, 4));
+        assert!(output.pixels().all(|p| *p == pixel));
+    }
+
+    #[test]
+    fn encodable_pixels_converts_only_unsupported_types() {
+        let rgb16 = DynamicImage::ImageRgb16(ImageBuffer::new(2, 2));
+        let rgba32f = DynamicImage::ImageRgba32F(ImageBuffer::new(2, 2));
+        let color = |img: &DynamicImage, format| encodable_pixels(img, format, false).color();
+
+        assert!(matches!(
+            encodable_pixels(&rgb16, OutputFormat::Png, false),
+            Cow::Borrowed(_)
+        ));
+        assert_eq!(color(&rgba32f, OutputFormat::Png), image::ColorType::Rgba16);
+        assert_eq!(color(&rgb16, OutputFormat::Jpeg), image::ColorType::Rgb8);
+        assert_eq!(color(&rgba32f, OutputFormat::WebP), image::ColorType::Rgba8);
+        assert_eq!(color(&rgb16, OutputFormat::Avif), image::ColorType::Rgb8);
+    }
+
+    #[test]
+    fn linear_light_keeps_the_brightness_of_fine_detail() {
+        let checkerboard = DynamicImage::ImageRgb8(RgbImage::from_fn(64, 64, |x, y| {
+            image::Rgb([((x + y) % 2 * 255) as u8; 3])
+        }));
+        let resize = |linear_light| {
+            let resized = resize_image(
+                &checkerboard,
+                Region::full(64, 64),
+                8,
+                8,
+                ResizeFilter::default(),
+                linear_light,
+            )
+            .unwrap();
+            resized.to_rgb8().get_pixel(4, 4)[0]
+        };
+
+        // ガンマのまま平均すると 50% の灰色（128）に暗く潰れる
+        assert!(resize(false).abs_diff(128) <= 2, "{}", resize(false));
+        // 線形光で平均すると、白と黒を半々に混ぜた明るさ（sRGB で約 188）になる
+        assert!(resize(true).abs_diff(188) <= 2, "{}", resize(true));
+    }
+}
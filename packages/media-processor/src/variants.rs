@@ -0,0 +1,126 @@
+//! 変換結果をストレージの `variants/` 以下に保存する永続キャッシュ（任意）。
+//!
+//! エッジのキャッシュから追い出された人気のサムネイルを何度も変換し直さないよう、変換結果を
+//! ストレージ（本番は Storage Proxy 経由の B2）に書き戻し、次回は原本の取得・変換より先に
+//! そちらを参照する。原本はアップロードごとに新しいキー（UUID）で保存され、同じキーの内容は
+//! 変わらないため、キーは他のキャッシュと同じくキャッシュキーだけで決まり、原本の ETag は含めない。
+//! 保存から一定期間を過ぎたものは Storage Proxy Worker の定期ジョブが削除する
+//! （ローカル・メモリのバックエンドでは削除しない）。
+
+use std::sync::Arc;
+
+use bytes::Bytes;
+
+use crate::cache::key_digest;
+use crate::config::env_bool;
+use crate::storage::{ObjectResponse, Storage, StorageError};
+use crate::transform::OutputFormat;
+
+/// 変換結果を保存するオブジェクトキーの接頭辞。
+const VARIANTS_PREFIX: &str = "variants/";
+
+#[derive(Clone)]
+pub struct VariantStore {
+    storage: Arc<dyn Storage>,
+}
+
+impl VariantStore {
+    /// 環境変数から VariantStore を作成する。無効な場合は None。
+    ///
+    /// 任意の環境変数:
+    /// - PERSIST_VARIANTS: 変換結果をストレージに保存するか（デフォルト: false）
+    pub fn from_env(storage: &Arc<dyn Storage>) -> Option<Self> {
+        if !env_bool("PERSIST_VARIANTS", false) {
+            return None;
+        }
+        tracing::info!(prefix = VARIANTS_PREFIX, "variant persistence enabled");
+        Some(Self {
+            storage: storage.clone(),
+        })
+    }
+
+    /// 保存済みの変換結果 (バイト列, content_type) を取得する。見つからない・取得に失敗した場合は None。
+    ///
+    /// 存在の確認に `HEAD` は送らず、`GET` の 404 をそのまま未保存として扱う。
+    pub async fn get(&self, cache_key: &str) -> Option<(Bytes, &'static str)> {
+        let key = variant_key(cache_key);
+        match self.storage.get(&key, None).await {
+            Ok(ObjectResponse::Modified {
+                data, content_type, ..
+            }) => {
+                let format = content_type
+                    .as_deref()
+                    .and_then(OutputFormat::from_content_type);
+                match format {
+                    Some(format) => Some((data, format.content_type())),
+                    None => {
+                        tracing::warn!(key = %key, content_type = ?content_type, "ignoring stored variant with unknown content type");
+                        None
+                    }
+                }
+            }
+            Ok(ObjectResponse::NotModified) | Err(StorageError::NotFound { .. }) => None,
+            Err(e) => {
+                // 保存済みの結果が使えなくても、原本から変換すれば返せる
+                tracing::warn!(key = %key, error = %e, "failed to fetch stored variant");
+                None
+            }
+        }
+    }
+
+    /// 変換結果を保存する。キーは内容で決まるため、別のインスタンスが保存済みでも同じ内容で
+    /// 上書きするだけ。失敗しても変換結果の返却には影響しないため、ログだけ残す。
+    pub async fn put(&self, cache_key: &str, output: &(Bytes, &'static str)) {
+        let key = variant_key(cache_key);
+        let (data, content_type) = output;
+        if let Err(e) = self.storage.put(&key, data.clone(), content_type).await {
+            tracing::warn!(key = %key, error = %e, "failed to store variant");
+        }
+    }
+}
+
+fn variant_key(cache_key: &str) -> String {
+    format!("{VARIANTS_PREFIX}{}", key_digest(cache_key))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStorage;
+
+    fn output() -> (Bytes, &'static str) {
+        (Bytes::from_static(b"variant"), "image/webp")
+    }
+
+    #[tokio::test]
+    async fn stored_variant_is_only_used_for_the_same_cache_key() {
+        let store = VariantStore {
+            storage: Arc::new(MemoryStorage::new()),
+        };
+        let cache_key = "v1|photo.jpg|w=320";
+
+        assert_eq!(store.get(cache_key).await, None);
+        store.put(cache_key, &output()).await;
+        assert_eq!(store.get(cache_key).await, Some(output()));
+        assert_eq!(store.get("v1|photo.jpg|w=640").await, None);
+    }
+
+    #[tokio::test]
+    async fn ignores_stored_object_with_unknown_content_type() {
+        let storage: Arc<dyn Storage> = Arc::new(MemoryStorage::new());
+        let store = VariantStore {
+            storage: storage.clone(),
+        };
+        let cache_key = "v1|photo.jpg|w=320";
+        storage
+            .put(
+                &variant_key(cache_key),
+                Bytes::from_static(b"variant"),
+                "text/plain",
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(store.get(cache_key).await, None);
+    }
+}
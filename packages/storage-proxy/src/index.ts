@@ -14,6 +14,7 @@ type Env = {
   APP_HOST: string;
   CF_ACCESS_TEAM_DOMAIN: string;
   CF_ACCESS_AUD: string;
+  VARIANT_RETENTION_DAYS?: string;
 };
 
 type HonoEnv = { Bindings: Env };
@@ -49,6 +50,12 @@ const UNSIGNABLE_HEADERS = [
 const HTTPS_PROTOCOL = "https:";
 const HTTPS_PORT = "443";
 const RANGE_RETRY_ATTEMPTS = 3;
+// Cloud Run が変換結果を書き戻すプレフィックス（書き込みはここだけ許可）
+const VARIANTS_PREFIX = "variants/";
+// 変換結果 1 件あたりの上限サイズ
+const MAX_VARIANT_BYTES = 32 * 1024 * 1024;
+// 1 回の定期クリーンアップで削除する最大件数（Worker の実行時間・サブリクエスト数の上限対策）
+const MAX_CLEANUP_DELETES = 500;
 
 function filterHeaders(headers: Headers, env: Env): Headers {
   const filteredHeaders: [string, string][] = [];
@@ -76,6 +83,14 @@ function createHeadResponse(response: Response): Response {
   });
 }
 
+function createB2Client(env: Env): AwsClient {
+  return new AwsClient({
+    accessKeyId: env.B2_KEY_ID,
+    secretAccessKey: env.B2_APP_KEY,
+    service: "s3",
+  });
+}
+
 function isListBucketRequest(env: Env, path: string): boolean {
   const pathSegments = path.split("/");
   return (
@@ -125,11 +140,7 @@ async function handleProxy(c: Context<HonoEnv>, method: "GET" | "HEAD") {
 
   const headers = filterHeaders(request.headers, env);
 
-  const client = new AwsClient({
-    accessKeyId: env.B2_KEY_ID,
-    secretAccessKey: env.B2_APP_KEY,
-    service: "s3",
-  });
+  const client = createB2Client(env);
 
   if (rcloneDownload) {
     if (env.BUCKET_NAME === "$path") {
@@ -231,9 +242,139 @@ app.use("*", async (c, next) => {
 });
 app.get("*", (c) => handleProxy(c, "GET"));
 app.on("HEAD", "*", (c) => handleProxy(c, "HEAD"));
+app.put("*", (c) => handleVariantPut(c));
 
 app.all("*", (c) => {
   return c.text("Method Not Allowed", 405);
 });
 
-export default app;
+// Cloud Run からの変換結果の書き戻し（variants/ 以下のみ）
+async function handleVariantPut(c: Context<HonoEnv>) {
+  // JWT はミドルウェアで検証済み。ヘッダがない Service Binding 経由（Edge Cache Worker）からの書き込みは拒否する
+  if (!c.req.header("Cf-Access-Jwt-Assertion")) {
+    return c.text("Forbidden", 403);
+  }
+
+  const path = new URL(c.req.url).pathname.substring(1);
+  const name = path.substring(VARIANTS_PREFIX.length);
+  if (!path.startsWith(VARIANTS_PREFIX) || !/^[0-9a-f]{64}$/.test(name)) {
+    return c.text("Forbidden", 403);
+  }
+
+  const bucketUrl = variantBucketUrl(c.env);
+  if (!bucketUrl) {
+    return c.text("Not Implemented", 501);
+  }
+
+  const body = await c.req.arrayBuffer();
+  if (body.byteLength > MAX_VARIANT_BYTES) {
+    return c.text("Payload Too Large", 413);
+  }
+
+  const client = createB2Client(c.env);
+  const response = await client.fetch(`${bucketUrl}/${path}`, {
+    body,
+    headers: {
+      "Content-Type":
+        c.req.header("Content-Type") ?? "application/octet-stream",
+    },
+    method: "PUT",
+  });
+  if (!response.ok) {
+    console.error(`変換結果の保存に失敗しました: ${path} (${response.status})`);
+    return c.text("Bad Gateway", 502);
+  }
+
+  return c.body(null, 204);
+}
+
+// 変換結果を保存するバケットの URL。バケットがリクエストごとに決まる設定（$path / $host）では使えない
+function variantBucketUrl(env: Env): string | undefined {
+  if (env.BUCKET_NAME === "$path" || env.BUCKET_NAME === "$host") {
+    return undefined;
+  }
+  return `${HTTPS_PROTOCOL}//${env.BUCKET_NAME}.${env.B2_ENDPOINT}`;
+}
+
+// 保存から VARIANT_RETENTION_DAYS 日を過ぎた変換結果を削除する定期ジョブ。
+// 最終アクセス日時ではなく保存日時（LastModified）で判定するため、参照されているものも
+// 期限が来れば削除され、次に要求されたときに再変換して保存し直される
+async function cleanupVariants(env: Env) {
+  const retentionDays = Number(env.VARIANT_RETENTION_DAYS);
+  if (!(retentionDays > 0)) {
+    return;
+  }
+
+  const bucketUrl = variantBucketUrl(env);
+  if (!bucketUrl) {
+    console.error(
+      "BUCKET_NAME が $path / $host のため、変換結果のクリーンアップをスキップします",
+    );
+    return;
+  }
+
+  const client = createB2Client(env);
+  const cutoff = Date.now() - retentionDays * 24 * 60 * 60 * 1000;
+  let continuationToken: string | undefined;
+  let deleted = 0;
+
+  do {
+    const listUrl = new URL(bucketUrl);
+    listUrl.searchParams.set("list-type", "2");
+    listUrl.searchParams.set("prefix", VARIANTS_PREFIX);
+    if (continuationToken) {
+      listUrl.searchParams.set("continuation-token", continuationToken);
+    }
+
+    const response = await client.fetch(listUrl.toString());
+    if (!response.ok) {
+      console.error(`変換結果の一覧取得に失敗しました (${response.status})`);
+      return;
+    }
+    const xml = await response.text();
+
+    for (const [, contents] of xml.matchAll(/<Contents>(.*?)<\/Contents>/gs)) {
+      const key = contents.match(/<Key>(.*?)<\/Key>/s)?.[1];
+      const lastModified = contents.match(
+        /<LastModified>(.*?)<\/LastModified>/s,
+      )?.[1];
+      if (!key || !lastModified || Date.parse(lastModified) >= cutoff) {
+        continue;
+      }
+
+      const deleteResponse = await client.fetch(`${bucketUrl}/${key}`, {
+        method: "DELETE",
+      });
+      if (!deleteResponse.ok) {
+        console.error(
+          `変換結果の削除に失敗しました: ${key} (${deleteResponse.status})`,
+        );
+        continue;
+      }
+      deleted += 1;
+      if (deleted >= MAX_CLEANUP_DELETES) {
+        console.log(
+          `変換結果を${deleted}件削除しました（上限に達したため残りは次回）`,
+        );
+        return;
+      }
+    }
+
+    continuationToken = xml.match(
+      /<NextContinuationToken>(.*?)<\/NextContinuationToken>/s,
+    )?.[1];
+  } while (continuationToken);
+
+  console.log(`変換結果を${deleted}件削除しました`);
+}
+
+export default {
+  fetch: app.fetch,
+  scheduled: (
+    _controller: ScheduledController,
+    env: Env,
+    ctx: ExecutionContext,
+  ) => {
+    ctx.waitUntil(cleanupVariants(env));
+  },
+} satisfies ExportedHandler<Env>;